@@ -0,0 +1,243 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::error::Error;
+use std::fmt;
+use std::path::Path;
+
+use crate::frame_meta::FrameMeta;
+use crate::process::{format_rank_ranges, Frame};
+
+/// Distribution of a training step/iteration variable across ranks.
+#[derive(Debug)]
+pub struct StepSkewReport {
+    /// Name of the local variable holding the step.
+    pub var: String,
+    /// Step value -> ranks at that step, ordered by step.
+    pub distribution: Vec<(String, BTreeSet<u32>)>,
+    /// Ranks whose stack has no frame capturing the variable.
+    pub missing: BTreeSet<u32>,
+    /// The step most ranks are at.
+    pub reference: Option<String>,
+    /// Ranks at a different numeric step than the reference, with their offset (negative means behind).
+    pub outliers: BTreeMap<u32, i64>,
+}
+
+/// Extracts the step variable of one rank's stack.
+/// The outermost Python frame capturing the variable wins, as that is usually the training loop.
+fn extract_step(trace: &[Frame], var: &str) -> Option<String> {
+    trace.iter().rev().find_map(|frame| match frame {
+        Frame::PyFrame(frame) => frame.local(var),
+        _ => None,
+    })
+}
+
+/// Compares the step variable `var` across ranks and flags ranks that are behind or ahead.
+/// `frames[i]` is the stack of `rank_list[i]`.
+pub fn detect_step_skew(frames: &[Vec<Frame>], rank_list: &[u32], var: &str) -> StepSkewReport {
+    let mut by_value: BTreeMap<String, BTreeSet<u32>> = BTreeMap::new();
+    let mut missing = BTreeSet::new();
+    for (trace, &rank) in frames.iter().zip(rank_list) {
+        match extract_step(trace, var) {
+            Some(value) => {
+                by_value.entry(value).or_default().insert(rank);
+            }
+            None => {
+                missing.insert(rank);
+            }
+        }
+    }
+    // Ranks without a fetched stack cannot report a step either
+    missing.extend(rank_list.iter().skip(frames.len()));
+
+    // The most common step is the reference; ties go to the most advanced step
+    let reference = by_value
+        .iter()
+        .max_by(|a, b| {
+            a.1.len()
+                .cmp(&b.1.len())
+                .then_with(|| a.0.parse::<i64>().ok().cmp(&b.0.parse::<i64>().ok()))
+        })
+        .map(|(value, _)| value.clone());
+
+    let mut outliers = BTreeMap::new();
+    if let Some(reference_step) = reference.as_ref().and_then(|value| value.parse::<i64>().ok()) {
+        for (value, ranks) in &by_value {
+            if let Ok(step) = value.parse::<i64>()
+                && step != reference_step
+            {
+                outliers.extend(ranks.iter().map(|&rank| (rank, step - reference_step)));
+            }
+        }
+    }
+
+    let mut distribution: Vec<_> = by_value.into_iter().collect();
+    distribution.sort_by(|a, b| {
+        a.0.parse::<i64>()
+            .ok()
+            .cmp(&b.0.parse::<i64>().ok())
+            .then_with(|| a.0.cmp(&b.0))
+    });
+
+    StepSkewReport {
+        var: var.to_string(),
+        distribution,
+        missing,
+        reference,
+        outliers,
+    }
+}
+
+impl fmt::Display for StepSkewReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "# Step skew for `{}`", self.var)?;
+        for (value, ranks) in &self.distribution {
+            let ranks: Vec<u32> = ranks.iter().cloned().collect();
+            let label = if ranks.len() == 1 { "rank" } else { "ranks" };
+            let marker = if self.reference.as_ref() == Some(value) { " (reference)" } else { "" };
+            writeln!(f, "{}={} on {} {}{}", self.var, value, label, format_rank_ranges(&ranks), marker)?;
+        }
+        for (rank, offset) in &self.outliers {
+            let direction = if *offset < 0 { "behind" } else { "ahead" };
+            writeln!(f, "WARNING: rank {} is {} step(s) {}", rank, offset.abs(), direction)?;
+        }
+        if self.distribution.len() > 1 && self.outliers.is_empty() {
+            writeln!(f, "WARNING: ranks report different non-numeric values")?;
+        }
+        if !self.missing.is_empty() {
+            let ranks: Vec<u32> = self.missing.iter().cloned().collect();
+            writeln!(f, "No `{}` local found on ranks {}", self.var, format_rank_ranges(&ranks))?;
+        }
+        if self.distribution.len() == 1 && self.missing.is_empty() {
+            writeln!(f, "All ranks are at the same step")?;
+        }
+        Ok(())
+    }
+}
+
+/// Differences between two merged stack files, e.g. two snapshots of the same hang.
+#[derive(Debug, Default)]
+pub struct StackDiff {
+    /// Merged stacks present only in the first file.
+    pub removed: Vec<String>,
+    /// Merged stacks present only in the second file.
+    pub added: Vec<String>,
+    /// Frames present in both files whose ranks changed: frame -> (ranks before, ranks after).
+    pub rank_changes: BTreeMap<String, (BTreeSet<u32>, BTreeSet<u32>)>,
+}
+
+/// Reads the stacks of a folded file without their sample counts.
+fn read_folded_stacks(path: &Path) -> Result<BTreeSet<String>, Box<dyn Error>> {
+    let content = std::fs::read_to_string(path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    Ok(content
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| line.rsplit_once(' ').map_or(line, |(stack, _)| stack).to_string())
+        .collect())
+}
+
+/// Compares two merged stack files. Rank changes are only reported when both files have frame annotations.
+pub fn diff_stack_files(before: &Path, after: &Path) -> Result<StackDiff, Box<dyn Error>> {
+    let before_stacks = read_folded_stacks(before)?;
+    let after_stacks = read_folded_stacks(after)?;
+    let mut diff = StackDiff {
+        removed: before_stacks.difference(&after_stacks).cloned().collect(),
+        added: after_stacks.difference(&before_stacks).cloned().collect(),
+        ..Default::default()
+    };
+
+    let before_meta = FrameMeta::load(&FrameMeta::sidecar_path(before));
+    let after_meta = FrameMeta::load(&FrameMeta::sidecar_path(after));
+    if let (Ok(before_meta), Ok(after_meta)) = (before_meta, after_meta) {
+        for (name, before_info) in &before_meta.frames {
+            if let Some(after_info) = after_meta.frames.get(name)
+                && before_info.ranks != after_info.ranks
+            {
+                diff.rank_changes
+                    .insert(name.clone(), (before_info.ranks.clone(), after_info.ranks.clone()));
+            }
+        }
+    }
+    Ok(diff)
+}
+
+impl fmt::Display for StackDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let ranges = |ranks: &BTreeSet<u32>| {
+            let ranks: Vec<u32> = ranks.iter().cloned().collect();
+            if ranks.is_empty() { "none".to_string() } else { format_rank_ranges(&ranks) }
+        };
+        writeln!(f, "# {} stack(s) only in the first file", self.removed.len())?;
+        for stack in &self.removed {
+            writeln!(f, "- {}", stack)?;
+        }
+        writeln!(f, "# {} stack(s) only in the second file", self.added.len())?;
+        for stack in &self.added {
+            writeln!(f, "+ {}", stack)?;
+        }
+        writeln!(f, "# {} frame(s) reached by different ranks", self.rank_changes.len())?;
+        for (name, (before, after)) in &self.rank_changes {
+            writeln!(f, "{}", name)?;
+            writeln!(f, "    ranks {} -> {}", ranges(before), ranges(after))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::process::parse_callstacks;
+    use std::fs;
+
+    /// A rank one step behind the others is flagged against the majority step.
+    #[test]
+    fn test_detect_step_skew() {
+        let json_data = fs::read_to_string("test/pyframe_locals.json").expect("Failed to read input file");
+        let frames: Vec<Vec<Frame>> = parse_callstacks(&json_data)
+            .expect("Invalid test data")
+            .into_iter()
+            .map(|stacks| stacks.into_threads().remove(0).frames)
+            .collect();
+
+        let report = detect_step_skew(&frames, &[0, 1, 2, 3, 4], "step");
+
+        assert_eq!(report.reference.as_deref(), Some("1200"));
+        assert_eq!(report.outliers, BTreeMap::from([(3, -1)]));
+        assert_eq!(report.missing, BTreeSet::from([4]));
+        assert!(report.to_string().contains("rank 3 is 1 step(s) behind"));
+    }
+
+    /// Stacks only present in one file and frames whose ranks moved are reported.
+    #[test]
+    fn test_diff_stack_files() {
+        let dir = std::env::temp_dir().join(format!("flame_test_diff_{}", std::process::id()));
+        fs::create_dir_all(&dir).expect("Failed to create test directory");
+        let before = dir.join("before.txt");
+        let after = dir.join("after.txt");
+        fs::write(&before, "main;train;all_reduce 1\nmain;train;load_batch 1\n").expect("Failed to write test input");
+        fs::write(&after, "main;train;all_reduce 1\n").expect("Failed to write test input");
+        let meta = |ranks: &[(&str, &[u32])]| FrameMeta {
+            frames: ranks
+                .iter()
+                .map(|(name, ranks)| {
+                    let info = crate::frame_meta::FrameInfo {
+                        ranks: ranks.iter().cloned().collect(),
+                        ..Default::default()
+                    };
+                    (name.to_string(), info)
+                })
+                .collect(),
+            ..Default::default()
+        };
+        meta(&[("all_reduce", &[0, 1]), ("load_batch", &[2])])
+            .save(&FrameMeta::sidecar_path(&before))
+            .expect("Failed to save annotations");
+        meta(&[("all_reduce", &[0, 1, 2])])
+            .save(&FrameMeta::sidecar_path(&after))
+            .expect("Failed to save annotations");
+
+        let diff = diff_stack_files(&before, &after).expect("Diff failed");
+        assert_eq!(diff.removed, vec!["main;train;load_batch".to_string()]);
+        assert!(diff.added.is_empty());
+        assert_eq!(diff.rank_changes["all_reduce"], (BTreeSet::from([0, 1]), BTreeSet::from([0, 1, 2])));
+    }
+}
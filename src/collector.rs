@@ -1,15 +1,77 @@
-use reqwest;
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use std::fs::File;
-use std::io::Write;
-use futures::future::join_all; 
-use chrono::Local;
-use std::path::PathBuf;
-use std::env;
+use std::time::{Duration, Instant};
+use tokio::net::TcpStream;
 
-/// Fetches JSON data from a list of URLs and saves the combined data to a file.
-pub async fn fetch_stack_from_urls(urls: Vec<String>) -> Result<String, Box<dyn std::error::Error>> {
-    let client = reqwest::Client::new();
+use crate::process::RankStacks;
+use crate::process_info::{ProcessInfo, OVERVIEW_PATH};
+
+/// Path of the Probing endpoint returning the call stacks of a training process.
+pub const CALLSTACK_PATH: &str = "/apis/pythonext/callstack";
+
+/// Query asking the call stack endpoint for every thread of the process.
+const ALL_THREADS_QUERY: &str = "?threads=all";
+
+/// Settings of the HTTP requests fetching call stacks, read from the config file.
+#[derive(Debug, Default, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CollectorConfig {
+    /// Path of the call stack endpoint [default: /apis/pythonext/callstack].
+    pub endpoint_path: Option<String>,
+    /// Timeout of each request in seconds [default: none].
+    pub timeout_secs: Option<u64>,
+    /// Path of the endpoint describing the process [default: /apis/overview].
+    pub overview_path: Option<String>,
+    /// Ask for the call stacks of all threads instead of the main thread only [default: false].
+    pub all_threads: Option<bool>,
+}
+
+impl CollectorConfig {
+    /// Fills the unset values of `self` from `base`.
+    pub fn or(self, base: CollectorConfig) -> CollectorConfig {
+        CollectorConfig {
+            endpoint_path: self.endpoint_path.or(base.endpoint_path),
+            timeout_secs: self.timeout_secs.or(base.timeout_secs),
+            overview_path: self.overview_path.or(base.overview_path),
+            all_threads: self.all_threads.or(base.all_threads),
+        }
+    }
+
+    /// Returns the call stack URL of a Probing endpoint at `ip:port`.
+    pub fn callstack_url(&self, address: &str) -> String {
+        let path = self.endpoint_path.as_deref().unwrap_or(CALLSTACK_PATH);
+        let query = if self.all_threads.unwrap_or(false) { ALL_THREADS_QUERY } else { "" };
+        format!("http://{}/{}{}", address, path.trim_start_matches('/'), query)
+    }
+
+    /// Returns the process overview URL of the endpoint serving `callstack_url`.
+    pub fn overview_url(&self, callstack_url: &str) -> Option<String> {
+        let mut url = reqwest::Url::parse(callstack_url).ok()?;
+        url.set_path(self.overview_path.as_deref().unwrap_or(OVERVIEW_PATH));
+        url.set_query(None);
+        Some(url.to_string())
+    }
+
+    pub fn timeout(&self) -> Option<Duration> {
+        self.timeout_secs.map(Duration::from_secs)
+    }
+
+    fn client(&self, timeout: Option<Duration>) -> reqwest::Result<reqwest::Client> {
+        let mut builder = reqwest::Client::builder();
+        if let Some(timeout) = timeout.or(self.timeout()) {
+            builder = builder.timeout(timeout);
+        }
+        builder.build()
+    }
+}
+
+/// Fetches JSON data from a list of URLs and combines it into one JSON array.
+/// Also returns the indexes into `urls` of the stacks in the array, which skips the failed URLs.
+pub async fn fetch_stack_from_urls(
+    urls: Vec<String>,
+    config: &CollectorConfig,
+) -> Result<(String, Vec<usize>), Box<dyn std::error::Error>> {
+    let client = config.client(None)?;
 
     let mut tasks = Vec::new();
     for url in urls {
@@ -26,9 +88,13 @@ pub async fn fetch_stack_from_urls(urls: Vec<String>) -> Result<String, Box<dyn
     let results: Vec<Result<Value, Box<dyn std::error::Error>>> = futures::future::join_all(tasks).await;
 
     let mut data_list = Vec::new();
-    for result in results {
+    let mut fetched = Vec::new();
+    for (index, result) in results.into_iter().enumerate() {
         match result {
-            Ok(json) => data_list.push(json),
+            Ok(json) => {
+                data_list.push(json);
+                fetched.push(index);
+            }
             Err(e) => eprintln!("Error: {}", e),
         }
     }
@@ -37,5 +103,261 @@ pub async fn fetch_stack_from_urls(urls: Vec<String>) -> Result<String, Box<dyn
 
     println!("Data has been processed successfully");
 
-    Ok(output)
-}
\ No newline at end of file
+    Ok((output, fetched))
+}
+
+/// Fetches the process metadata of the endpoints serving `urls`, in the same order. Endpoints
+/// without an overview, e.g. older Probing versions, give `None` with a warning.
+pub async fn fetch_process_info(urls: &[String], config: &CollectorConfig) -> Vec<Option<ProcessInfo>> {
+    let client = match config.client(None) {
+        Ok(client) => client,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            return vec![None; urls.len()];
+        }
+    };
+    let tasks = urls.iter().map(|url| {
+        let client = client.clone();
+        let overview_url = config.overview_url(url);
+        async move {
+            let overview_url = overview_url.ok_or_else(|| format!("Invalid URL {}", url))?;
+            let res = client.get(&overview_url).send().await.map_err(|e| e.to_string())?;
+            if !res.status().is_success() {
+                return Err(format!("{} returned {}", overview_url, res.status()));
+            }
+            let overview: Value = res.json().await.map_err(|e| e.to_string())?;
+            Ok(ProcessInfo::from_overview(&overview))
+        }
+    });
+
+    let results: Vec<Result<ProcessInfo, String>> = futures::future::join_all(tasks).await;
+    // 所有端点都不支持时只提示一次
+    if let Some(Err(e)) = results.first()
+        && results.iter().all(Result::is_err)
+    {
+        eprintln!("Warning: No process metadata from any endpoint: {}", e);
+        return vec![None; results.len()];
+    }
+    results
+        .into_iter()
+        .map(|result| result.map_err(|e| eprintln!("Warning: No process metadata: {}", e)).ok())
+        .collect()
+}
+
+/// Checks which `ip:port` addresses serve the call stack endpoint, waiting at most `timeout` for each.
+pub async fn probe_endpoints(addresses: &[String], config: &CollectorConfig, timeout: Duration) -> Vec<bool> {
+    let client = match config.client(Some(timeout)) {
+        Ok(client) => client,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            return vec![false; addresses.len()];
+        }
+    };
+    let tasks = addresses.iter().map(|address| {
+        let client = client.clone();
+        let url = config.callstack_url(address);
+        async move {
+            match client.get(&url).send().await {
+                Ok(res) => res.status().is_success(),
+                Err(_) => false,
+            }
+        }
+    });
+    futures::future::join_all(tasks).await
+}
+
+/// Health of one rank's call stack endpoint.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct EndpointHealth {
+    pub rank: Option<u32>,
+    pub url: String,
+    /// Whether a TCP connection to the endpoint could be opened.
+    pub reachable: bool,
+    pub tcp_latency_ms: Option<f64>,
+    /// Time until the whole HTTP response was received.
+    pub http_latency_ms: Option<f64>,
+    pub http_status: Option<u16>,
+    /// Number of frames in the response, if it parsed as a call stack.
+    pub frames: Option<usize>,
+    /// Why the check failed.
+    pub error: Option<String>,
+}
+
+impl EndpointHealth {
+    pub fn is_ok(&self) -> bool {
+        self.error.is_none()
+    }
+}
+
+fn elapsed_ms(start: Instant) -> f64 {
+    start.elapsed().as_secs_f64() * 1000.0
+}
+
+/// Checks one endpoint: TCP connect, HTTP request, then whether the body parses as a call stack
+/// made of known frame kinds.
+async fn check_endpoint(client: &reqwest::Client, rank: Option<u32>, url: String, timeout: Duration) -> EndpointHealth {
+    let mut health = EndpointHealth { rank, url, ..Default::default() };
+
+    let address = reqwest::Url::parse(&health.url)
+        .ok()
+        .and_then(|url| Some(format!("{}:{}", url.host_str()?, url.port_or_known_default()?)));
+    let Some(address) = address else {
+        health.error = Some("invalid URL".to_string());
+        return health;
+    };
+    let start = Instant::now();
+    match tokio::time::timeout(timeout, TcpStream::connect(&address)).await {
+        Ok(Ok(_)) => {
+            health.reachable = true;
+            health.tcp_latency_ms = Some(elapsed_ms(start));
+        }
+        Ok(Err(e)) => {
+            health.error = Some(format!("TCP connect failed: {}", e));
+            return health;
+        }
+        Err(_) => {
+            health.error = Some(format!("TCP connect timed out after {:?}", timeout));
+            return health;
+        }
+    }
+
+    let start = Instant::now();
+    let body = match client.get(&health.url).send().await {
+        Ok(res) => {
+            health.http_status = Some(res.status().as_u16());
+            let status = res.status();
+            let body = res.text().await;
+            health.http_latency_ms = Some(elapsed_ms(start));
+            match body {
+                Ok(_) if !status.is_success() => {
+                    health.error = Some(format!("HTTP {}", status));
+                    return health;
+                }
+                Ok(body) => body,
+                Err(e) => {
+                    health.error = Some(format!("Failed to read the response: {}", e));
+                    return health;
+                }
+            }
+        }
+        Err(e) => {
+            health.error = Some(format!("HTTP request failed: {}", e));
+            return health;
+        }
+    };
+
+    // 未知类型的栈帧能兼容地合并，但端点检查要求返回可识别的调用栈
+    match serde_json::from_str::<RankStacks>(&body) {
+        Ok(stacks) => {
+            let warnings = stacks.schema_warnings();
+            if !warnings.is_empty() {
+                health.error = Some(format!("Response is not a call stack: {}", warnings.join("; ")));
+            } else if stacks.known_frame_count() == 0 {
+                health.error = Some("Response is not a call stack: no frame of a known kind".to_string());
+            } else {
+                health.frames = Some(stacks.frame_count());
+            }
+        }
+        Err(e) => health.error = Some(format!("Response is not a call stack: {}", e)),
+    }
+    health
+}
+
+/// Checks the call stack endpoints of the ranks concurrently, waiting at most `timeout` for each step.
+/// `ranks` pairs each URL with its rank, when known.
+pub async fn check_endpoints(
+    ranks: Vec<(Option<u32>, String)>,
+    config: &CollectorConfig,
+    timeout: Duration,
+) -> Result<Vec<EndpointHealth>, reqwest::Error> {
+    let client = config.client(Some(timeout))?;
+    let tasks = ranks
+        .into_iter()
+        .map(|(rank, url)| {
+            let client = client.clone();
+            async move { check_endpoint(&client, rank, url, timeout).await }
+        });
+    Ok(futures::future::join_all(tasks).await)
+}
+
+/// Formats endpoint health as a table, one rank per row.
+pub fn health_table(results: &[EndpointHealth]) -> String {
+    let ms = |value: Option<f64>| value.map(|ms| format!("{:.1}ms", ms)).unwrap_or_else(|| "-".to_string());
+    let mut table = format!(
+        "{:<6} {:<48} {:>9} {:>9} {:>6} {:>6}  {}\n",
+        "RANK", "URL", "TCP", "HTTP", "STATUS", "FRAMES", "RESULT"
+    );
+    for health in results {
+        table.push_str(&format!(
+            "{:<6} {:<48} {:>9} {:>9} {:>6} {:>6}  {}\n",
+            health.rank.map(|rank| rank.to_string()).unwrap_or_else(|| "?".to_string()),
+            health.url,
+            ms(health.tcp_latency_ms),
+            ms(health.http_latency_ms),
+            health.http_status.map(|status| status.to_string()).unwrap_or_else(|| "-".to_string()),
+            health.frames.map(|frames| frames.to_string()).unwrap_or_else(|| "-".to_string()),
+            health.error.as_deref().unwrap_or("ok"),
+        ));
+    }
+    table
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    /// Starts a mock endpoint answering every request with `body`, returning its address.
+    async fn serve(body: &'static str) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.expect("Failed to bind mock endpoint");
+        let address = listener.local_addr().expect("No local address").to_string();
+        // 第一个连接来自TCP探测，不发送请求
+        tokio::spawn(async move {
+            while let Ok((mut socket, _)) = listener.accept().await {
+                let mut buffer = [0; 4096];
+                if socket.read(&mut buffer).await.unwrap_or(0) == 0 {
+                    continue;
+                }
+                let response =
+                    format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", body.len(), body);
+                let _ = socket.write_all(response.as_bytes()).await;
+            }
+        });
+        address
+    }
+
+    /// A serving endpoint reports its frames; a closed port is reported unreachable, and an array
+    /// of something other than frames fails the check.
+    #[tokio::test]
+    async fn test_check_endpoints() {
+        let address = serve(r#"[{"PyFrame": {"file": "train.py", "func": "main", "lineno": 3, "locals": {}}}]"#).await;
+        let not_frames = serve(r#"["error", 1, 2]"#).await;
+        let closed = TcpListener::bind("127.0.0.1:0").await.expect("Failed to bind");
+        let closed_address = closed.local_addr().expect("No local address").to_string();
+        drop(closed);
+
+        let config = CollectorConfig::default();
+        let results = check_endpoints(
+            vec![
+                (Some(0), config.callstack_url(&address)),
+                (Some(1), config.callstack_url(&closed_address)),
+                (Some(2), config.callstack_url(&not_frames)),
+            ],
+            &config,
+            Duration::from_secs(5),
+        )
+        .await
+        .expect("Failed to build client");
+
+        assert!(results[0].is_ok(), "{:?}", results[0]);
+        assert_eq!(results[0].http_status, Some(200));
+        assert_eq!(results[0].frames, Some(1));
+        assert!(!results[1].reachable);
+        assert!(!results[1].is_ok());
+        assert!(results[2].reachable);
+        assert!(!results[2].is_ok(), "{:?}", results[2]);
+        assert!(results[2].error.as_deref().unwrap_or_default().starts_with("Response is not a call stack"));
+        assert!(health_table(&results).lines().nth(1).expect("Row of rank 0").ends_with("ok"));
+    }
+}
@@ -1,50 +1,124 @@
-use clap::{Command, Parser, Arg};
-use crate::collector::fetch_stack_from_urls;
-use crate::process::process_and_merge_callstacks;
-use crate::draw_flame::draw_frame_graph;
-use serde_json::from_str;
+use clap::{Args, Command, FromArgMatches, Parser, Subcommand};
+use crate::analysis::diff_stack_files;
+use crate::collector::{
+    check_endpoints, fetch_process_info, fetch_stack_from_urls, health_table, probe_endpoints, CollectorConfig,
+};
+use crate::config::Config;
+use crate::process_info::{processes_path, IdentityCheck, ProcessInfo};
+use crate::proc_tree::{listening_addresses, local_ranks, read_environ};
+use crate::process::{attach_workers, format_rank_ranges, merge_callstacks, process_and_merge_callstacks, MergeOptions, ThreadMode};
+use crate::symbolize::{parse_maps_arg, SymbolizeOptions};
+use crate::frame_meta::FrameMeta;
+use crate::importers::{import_dump, rank_from_file_name, DumpFormat};
+use crate::kubernetes::{pod_targets, KubeAccess, PodRankOptions, PodTarget, RANK_ANNOTATION, RANK_ENV};
+use crate::run::{Manifest, RunDir, MANIFEST};
+use crate::targets::{expand_nodelist, expand_rank_args, node_rank_targets, read_hostfile};
+use crate::draw_flame::{draw_frame_graph, draw_frame_graph_batch, BatchOptions, DrawOptions};
+use chrono::Local;
+use std::collections::BTreeMap;
 use std::error::Error;
 use std::fs::File;
-use std::io::{Read, Write};
-use std::path::PathBuf;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
 /// 主命令结构体
+/// 不带子命令时兼容旧的 -i/-f/-r 用法：-i 等同于 `draw -i`，-f/-r 等同于 `merge -f/-r`
 #[derive(Parser, Debug)]
 #[command(
     name = "flame",
     about = "Perform call stack collection, processing, and frame graph drawing tasks.",
-    long_about = "This tool can collect call stack information from URLs, process call stack data, and draw frame graphs based on the processed results."
+    long_about = "This tool can collect call stack information from URLs, process call stack data, and draw frame graphs based on the processed results.\n\n\
+                  Without a subcommand, the legacy flags are accepted: -i works like `flame draw -i` and -f/-r like `flame merge -f/-r`.",
+    args_conflicts_with_subcommands = true
 )]
 struct Cli {
-    /// 基于合并后的调用栈文件绘制火焰图（互斥选项）
-    #[arg(
-        short = 'i',
-        long = "input",
-        group = "action",
-        help = "Path to the merged call stack file, used as the basis for drawing the frame graph."
-    )]
-    draw_input: Option<String>,
+    #[command(subcommand)]
+    command: Option<Commands>,
 
     /// 统一用于指定输出目录路径
     #[arg(
         short = 'o',
         long = "output",
-        value_parser = |input_path_str: &str| -> Result<String, String> {
-            let input_path = PathBuf::from(input_path_str);
-            if input_path.is_dir() {
-                return Ok(input_path.to_string_lossy().to_string());
-            }
-            Err("Output path must be a valid directory".to_string())
-        },
-        help = "Path to the output directory for storing the generated frame graph, the processed call stack information, or the rank configuration JSON file."
+        global = true,
+        value_parser = parse_output_dir,
+        help = "Base directory of the run directories. Each collect, merge or draw creates a run_<timestamp> directory in it \
+                holding its raw data, merged stacks, flamegraphs, reports and manifest.json [default: $TMPDIR/flame]. \
+                discover writes urls.json directly into it."
     )]
     output: Option<String>,
 
-    /// 从 URL 列表获取调用栈信息并处理（互斥选项）
+    /// 配置文件路径
+    #[arg(
+        long = "config",
+        value_name = "PATH",
+        global = true,
+        help = "TOML config file layered over ~/.config/flame/config.toml and ./flame.toml. \
+                FLAME_* environment variables override it and command-line flags override everything."
+    )]
+    config: Option<PathBuf>,
+
+    /// 基于合并后的调用栈文件绘制火焰图（兼容旧用法，同 `draw -i`）
+    #[arg(
+        short = 'i',
+        long = "input",
+        conflicts_with_all = ["fetch_file", "ranks", "nodelist", "hostfile"],
+        help = "Path to the merged call stack file, used as the basis for drawing the frame graph. Same as `flame draw -i`."
+    )]
+    draw_input: Option<String>,
+
+    /// 兼容旧用法的采集目标，同 `merge -f/-r`
+    #[command(flatten)]
+    targets: TargetArgs,
+
+    #[command(flatten)]
+    merge: MergeArgs,
+
+    #[command(flatten)]
+    pipeline: PipelineArgs,
+
+    #[command(flatten)]
+    draw: DrawArgs,
+}
+
+/// 构建命令行解析器
+pub fn build_cli() -> Command {
+    <Cli as clap::CommandFactory>::command()
+}
+
+/// 子命令
+#[derive(Subcommand, Debug)]
+enum Commands {
+    /// Fetch the call stacks of the ranks and save them as raw JSON without merging.
+    Collect(CollectCommand),
+    /// Merge call stacks fetched from the ranks or read from a raw JSON file into a stack file,
+    /// optionally drawing its flamegraph right away.
+    Merge(Box<MergeCommand>),
+    /// Convert stack dumps of py-spy, GDB or perf script into a raw call stack file for `merge --raw`.
+    Import(ImportCommand),
+    /// Draw flamegraphs from a merged stack file.
+    Draw(DrawCommand),
+    /// Print how the ranks' call stacks group together, plus the step skew and locals reports.
+    Analyze(AnalyzeCommand),
+    /// Compare two merged stack files, e.g. two snapshots of the same hang.
+    Diff(DiffCommand),
+    /// Check that the ranks' endpoints are reachable and serve parseable call stacks.
+    #[command(alias = "ping")]
+    Check(CheckCommand),
+    /// Probe hosts or Kubernetes pods for Probing endpoints and write the URL file used by -f.
+    Discover(DiscoverCommand),
+    /// Inspect the configuration read from config files and the environment.
+    Config(ConfigCommand),
+}
+
+/// 采集目标：URL配置文件或逐个指定的rank地址
+#[derive(Args, Debug, Default, Clone)]
+struct TargetArgs {
+    /// 从 URL 列表获取调用栈信息并处理
     #[arg(
         short = 'f',
         long = "file",
-        group = "action",
-        help = "Path to the file containing a JSON array of URLs."
+        help = "Path to the JSON file mapping ranks to addresses, e.g. {\"rank0\": \"10.0.0.5:11490\"}."
     )]
     fetch_file: Option<String>,
 
@@ -52,123 +126,1290 @@ struct Cli {
         short = 'r',
         long = "rank",
         num_args = 1,
-        value_name = "RANK:<IP:PORT>",
+        value_name = "RANKS:HOSTS:PORT[+]",
         action = clap::ArgAction::Append,
-        help = "Specify the rank, IP address, and port number to get the call stack from the corresponding address. \
-                The format should be RANK:<IP:PORT>, and this option can be used multiple times."
+        help = "Specify the ranks, hosts and port to get the call stacks from, e.g. 2:10.0.0.5:11492. RANKS may be a range \
+                or list (0-7 or 0-3,8-11) and HOSTS a comma-separated list of IPs, hostnames or bracketed IPv6 addresses; \
+                the ranks are split evenly over the hosts, and a trailing '+' gives the ranks of a host consecutive ports \
+                from PORT, e.g. 0-15:gpu001,gpu002:11490+. @FILE reads one spec per line from FILE. \
+                This option can be used multiple times. Takes precedence over -f."
     )]
     ranks: Vec<String>,
+
+    /// Slurm节点列表
+    #[arg(
+        long = "nodelist",
+        value_name = "NODELIST",
+        conflicts_with_all = ["ranks", "hostfile"],
+        help = "Slurm nodelist of the job, e.g. gpu[001-016,020] as in $SLURM_JOB_NODELIST. Ranks are numbered in node order, \
+                --gpus-per-node on each node listening on consecutive ports from --base-port. Takes precedence over -f."
+    )]
+    nodelist: Option<String>,
+
+    /// MPI格式的hostfile
+    #[arg(
+        long = "hostfile",
+        value_name = "PATH",
+        conflicts_with = "ranks",
+        help = "MPI-style hostfile with one node per line, e.g. `gpu001 slots=8` or `gpu001:8`. Ranks are numbered like \
+                with --nodelist; the slots of a node override --gpus-per-node. Takes precedence over -f."
+    )]
+    hostfile: Option<PathBuf>,
+
+    /// 采集所有线程的调用栈
+    #[arg(
+        long = "all-threads",
+        help = "Ask the Probing endpoints for the call stacks of all threads, such as the NCCL watchdog, \
+                DataLoader and checkpoint threads, instead of the main thread only."
+    )]
+    all_threads: bool,
+
+    /// 每个节点的rank数
+    #[arg(
+        long = "gpus-per-node",
+        value_name = "N",
+        default_value_t = 8,
+        help = "Number of ranks on each node of --nodelist, or of --hostfile nodes without slots."
+    )]
+    gpus_per_node: u32,
+
+    /// 每个节点的起始端口号
+    #[arg(
+        long = "base-port",
+        value_name = "PORT",
+        default_value_t = 11490,
+        help = "Port of the first rank on each node of --nodelist or --hostfile, as configured by config_probing.sh."
+    )]
+    base_port: u16,
 }
 
-/// 构建命令行解析器
-pub fn build_cli() -> Command {
-    <Cli as clap::CommandFactory>::command()
+impl TargetArgs {
+    fn is_set(&self) -> bool {
+        self.fetch_file.is_some() || !self.ranks.is_empty() || self.nodelist.is_some() || self.hostfile.is_some()
+    }
+
+    /// Resolves the ranks to collect from; -r takes precedence over --nodelist/--hostfile, then -f, then
+    /// the rank addresses of the config file. Nodes set in the config file override the address hosts.
+    fn resolve(&self, config: &Config) -> Result<Targets, Box<dyn Error>> {
+        let collector = CollectorConfig {
+            all_threads: self.all_threads.then_some(true),
+            ..Default::default()
+        }
+        .or(config.collector.clone());
+        let mut targets = if !self.ranks.is_empty() {
+            parse_rank_args(&self.ranks, &collector)?
+        } else if self.nodelist.is_some() || self.hostfile.is_some() {
+            self.node_targets(&collector)?
+        } else if let Some(file) = &self.fetch_file {
+            load_url_file(file, &collector)?
+        } else {
+            targets_from_config(&collector, config)?
+        };
+        for (rank, entry) in config.rank_entries()? {
+            if let Some(node) = &entry.node
+                && targets.rank_list.contains(&rank)
+            {
+                targets.rank_nodes.insert(rank, node.clone());
+            }
+        }
+        Ok(targets)
+    }
+
+    /// Generates the rank addresses of the --nodelist or --hostfile nodes.
+    fn node_targets(&self, collector: &CollectorConfig) -> Result<Targets, Box<dyn Error>> {
+        let nodes: Vec<(String, u32)> = match (&self.nodelist, &self.hostfile) {
+            (Some(nodelist), _) => expand_nodelist(nodelist)?
+                .into_iter()
+                .map(|host| (host, self.gpus_per_node))
+                .collect(),
+            (None, Some(hostfile)) => read_hostfile(hostfile)?
+                .into_iter()
+                .map(|entry| (entry.host, entry.slots.unwrap_or(self.gpus_per_node)))
+                .collect(),
+            (None, None) => Vec::new(),
+        };
+
+        let mut targets = Targets::default();
+        for (rank, address) in node_rank_targets(&nodes, self.base_port)? {
+            targets.rank_list.push(rank);
+            targets.rank_nodes.insert(rank, node_of_address(&address));
+            targets.urls.push(collector.callstack_url(&address));
+        }
+        if targets.urls.is_empty() {
+            return Err("No ranks generated from --nodelist/--hostfile".into());
+        }
+        eprintln!("Generated {} ranks on {} nodes", targets.rank_list.len(), nodes.len());
+        Ok(targets)
+    }
+}
+
+/// 合并选项
+#[derive(Args, Debug, Default, Clone)]
+struct MergeArgs {
+    /// 需要按rank汇总的Python局部变量名
+    #[arg(
+        long = "locals",
+        value_name = "NAME,...",
+        value_delimiter = ',',
+        help = "Names of Python local variables (e.g. step,iteration) whose per-rank values are summarized \
+                at each frame in the locals report and the flamegraph tooltips."
+    )]
+    locals: Vec<String>,
+
+    /// 用于检测训练步偏差的Python局部变量名
+    #[arg(
+        long = "step-var",
+        value_name = "NAME",
+        help = "Name of the Python local variable holding the training step or iteration (e.g. step). \
+                Its distribution across ranks is reported and ranks behind or ahead are flagged."
+    )]
+    step_var: Option<String>,
+
+    /// 进程内存映射文件，用于离线解析C帧的源码位置
+    #[arg(
+        long = "maps",
+        value_name = "[RANK:]PATH",
+        action = clap::ArgAction::Append,
+        value_parser = parse_maps_arg,
+        help = "Memory map of a training process in /proc/<pid>/maps format, used to resolve file:line of native frames \
+                from their instruction pointers. Prefix with RANK: to apply it to one rank only; can be used multiple times."
+    )]
+    maps: Vec<(Option<u32>, PathBuf)>,
+
+    /// 共享库本地副本所在目录
+    #[arg(
+        long = "symbol-dir",
+        value_name = "DIR",
+        action = clap::ArgAction::Append,
+        help = "Directory holding local copies of the mapped shared libraries with DWARF data, \
+                either mirroring their absolute paths or flat by file name. Can be used multiple times."
+    )]
+    symbol_dirs: Vec<PathBuf>,
+
+    /// 以叶子帧为根合并调用栈
+    #[arg(
+        long = "icicle",
+        help = "Re-root the merged stacks at their leaf frames, so ranks blocked in the same place are grouped together \
+                regardless of how they got there. The flamegraph of such a file is drawn as an icicle graph."
+    )]
+    icicle: bool,
+
+    /// 多线程调用栈的合并方式
+    #[arg(
+        long = "threads",
+        value_name = "MODE",
+        value_enum,
+        help = "How the threads of each rank are merged when all threads were collected (see --all-threads): \
+                under a level naming their role (main, nccl-watchdog, dataloader, checkpoint, autograd, other), \
+                their name, or their name and ID, or keep only the main thread [default: role]."
+    )]
+    threads: Option<ThreadMode>,
+
+    /// rank身份不一致时仍然合并
+    #[arg(
+        long = "force",
+        help = "Merge even if the RANK reported by a target process differs from its configured rank, \
+                e.g. because a stale URL file points at another rank's process after a restart."
+    )]
+    force: bool,
+}
+
+impl MergeArgs {
+    fn options(self, config: &Config) -> MergeOptions {
+        MergeOptions {
+            locals: self.locals,
+            step_var: self.step_var,
+            symbolize: SymbolizeOptions {
+                maps: self.maps,
+                symbol_dirs: self.symbol_dirs,
+            },
+            icicle: self.icicle,
+            threads: self.threads.unwrap_or_default(),
+            filter: config.filter.clone(),
+            ..Default::default()
+        }
+    }
 }
 
-/// 合并 fetch_and_save_urls 和 process_and_merge_callstacks 为一个函数
-pub async fn fetch_process_and_merge(url_file: &str, output: Option<&str>) -> Result<(), Box<dyn Error>> {
+/// 采集、合并、绘制一次完成的相关选项
+#[derive(Args, Debug, Default, Clone)]
+struct PipelineArgs {
+    /// 采集合并后直接绘制火焰图
+    #[arg(
+        long = "draw",
+        help = "Draw the flamegraph right after collecting and merging the call stacks, \
+                instead of running the tool again with -i. All written files are listed at the end."
+    )]
+    pipeline: bool,
+
+    /// 不保存采集到的原始调用栈数据
+    #[arg(
+        long = "no-raw",
+        help = "Do not save the call stacks fetched from the ranks as JSON next to the merged stack file (<name>.raw.json), \
+                which is done by default so the run can be merged again with --raw."
+    )]
+    no_raw: bool,
+
+    /// 绘制完成后删除中间文件
+    #[arg(
+        long = "discard-intermediate",
+        requires = "pipeline",
+        help = "With --draw, delete the merged stack file and its frame annotations once the flamegraph is drawn, \
+                keeping only the flamegraphs, reports and the fetched raw data."
+    )]
+    discard_intermediate: bool,
+}
+
+/// 火焰图绘制相关选项
+#[derive(Args, Debug, Default, Clone)]
+struct DrawArgs {
+    /// 火焰图绘制选项
+    #[command(flatten)]
+    draw: DrawOptions,
+
+    /// 按rank/节点批量绘制火焰图
+    #[command(flatten)]
+    batch: BatchOptions,
+}
+
+impl DrawArgs {
+    /// Layers the command-line drawing flags over the `[draw]` settings of the config.
+    fn options(&self, config: &Config) -> DrawOptions {
+        self.draw.clone().or(config.draw.clone())
+    }
+
+    /// Draws the flamegraphs of a merged stack file into the run directory, returning the SVG
+    /// or, in batch mode, the index page.
+    fn draw(&self, input: &str, run: &mut RunDir, config: &Config) -> Result<PathBuf, Box<dyn Error>> {
+        let draw_options = self.options(config);
+        run.manifest.set_settings("draw", &draw_options)?;
+        let path = if self.batch.is_enabled() {
+            draw_frame_graph_batch(input, run.path(), &draw_options, &self.batch)?
+        } else {
+            draw_frame_graph(input, run.path(), &draw_options)?
+        };
+        println!("Frame graph has been drawn successfully");
+        Ok(path)
+    }
+}
+
+#[derive(Args, Debug)]
+struct CollectCommand {
+    #[command(flatten)]
+    targets: TargetArgs,
+}
+
+#[derive(Args, Debug)]
+struct ImportCommand {
+    /// 各rank的堆栈文件
+    #[arg(
+        value_name = "FILE",
+        required = true,
+        help = "Stack dump of one rank per file, e.g. the output of `py-spy dump --json --pid <PID>` \
+                or `gdb -p <PID> -batch -ex 'thread apply all bt'` saved as rank3.json or rank3.txt."
+    )]
+    files: Vec<PathBuf>,
+
+    /// 堆栈文件格式
+    #[arg(
+        long = "format",
+        value_name = "FORMAT",
+        value_enum,
+        default_value_t = DumpFormat::Auto,
+        help = "Tool the dumps come from; perf script dumps use the last sample of each thread as its stack \
+                [default: guessed from the content of each file]."
+    )]
+    format: DumpFormat,
+
+    /// 各文件对应的rank
+    #[arg(
+        long = "rank-list",
+        value_name = "RANK,...",
+        value_delimiter = ',',
+        help = "Ranks of the files, in order [default: the number after 'rank' in each file name, or the file name \
+                if it is a number such as 3.txt]."
+    )]
+    rank_list: Vec<u32>,
+}
+
+#[derive(Args, Debug)]
+struct MergeCommand {
+    #[command(flatten)]
+    targets: TargetArgs,
+
+    /// 从已保存的原始调用栈文件合并
+    #[arg(
+        long = "raw",
+        value_name = "PATH",
+        conflicts_with_all = ["fetch_file", "ranks", "nodelist", "hostfile"],
+        help = "Merge the call stacks saved by `flame collect` or an earlier merge instead of fetching them."
+    )]
+    raw: Option<PathBuf>,
+
+    /// 原始调用栈对应的rank列表
+    #[arg(
+        long = "rank-list",
+        value_name = "RANK,...",
+        value_delimiter = ',',
+        requires = "raw",
+        help = "Ranks of the stacks in the --raw file, in order [default: 0, 1, 2, ...]."
+    )]
+    rank_list: Vec<u32>,
+
+    #[command(flatten)]
+    merge: MergeArgs,
+
+    #[command(flatten)]
+    pipeline: PipelineArgs,
+
+    #[command(flatten)]
+    draw: DrawArgs,
+}
+
+#[derive(Args, Debug)]
+struct DrawCommand {
+    #[arg(
+        short = 'i',
+        long = "input",
+        help = "Path to the merged call stack file, used as the basis for drawing the frame graph."
+    )]
+    input: String,
+
+    #[command(flatten)]
+    draw: DrawArgs,
+}
+
+#[derive(Args, Debug)]
+struct AnalyzeCommand {
+    #[command(flatten)]
+    targets: TargetArgs,
+
+    #[arg(
+        long = "raw",
+        value_name = "PATH",
+        conflicts_with_all = ["fetch_file", "ranks", "nodelist", "hostfile"],
+        help = "Analyze the call stacks saved by `flame collect` or an earlier merge instead of fetching them."
+    )]
+    raw: Option<PathBuf>,
+
+    #[arg(
+        long = "rank-list",
+        value_name = "RANK,...",
+        value_delimiter = ',',
+        requires = "raw",
+        help = "Ranks of the stacks in the --raw file, in order [default: 0, 1, 2, ...]."
+    )]
+    rank_list: Vec<u32>,
+
+    #[command(flatten)]
+    merge: MergeArgs,
+}
+
+#[derive(Args, Debug)]
+struct DiffCommand {
+    /// 较早的合并调用栈文件
+    #[arg(value_name = "BEFORE", help = "The earlier merged stack file.")]
+    before: PathBuf,
+
+    /// 较新的合并调用栈文件
+    #[arg(value_name = "AFTER", help = "The later merged stack file.")]
+    after: PathBuf,
+}
+
+#[derive(Args, Debug)]
+struct CheckCommand {
+    #[command(flatten)]
+    targets: TargetArgs,
+
+    #[arg(
+        long = "timeout-ms",
+        value_name = "MS",
+        help = "How long to wait for the connection and for the response of each endpoint \
+                [default: the collector timeout of the config, or 5000]."
+    )]
+    timeout_ms: Option<u64>,
+
+    /// 以JSON格式输出
+    #[arg(long = "json", help = "Print the results as JSON instead of a table.")]
+    json: bool,
+}
+
+#[derive(Args, Debug)]
+struct DiscoverCommand {
+    /// 需要探测的节点
+    #[arg(
+        long = "host",
+        value_name = "IP",
+        required_unless_present_any = ["selector", "local"],
+        conflicts_with_all = ["selector", "local"],
+        action = clap::ArgAction::Append,
+        help = "Host running training processes; can be used multiple times. Ranks are numbered in host order."
+    )]
+    hosts: Vec<String>,
+
+    /// 起始端口号
+    #[arg(
+        long = "start-port",
+        value_name = "PORT",
+        default_value_t = 11490,
+        help = "Port of the first process on each host, as configured by config_probing.sh."
+    )]
+    start_port: u16,
+
+    /// 每个节点的进程数
+    #[arg(
+        long = "procs-per-host",
+        value_name = "N",
+        default_value_t = 8,
+        help = "Number of training processes per host, listening on consecutive ports."
+    )]
+    procs_per_host: u16,
+
+    /// Kubernetes pod标签选择器
+    #[arg(
+        long = "selector",
+        value_name = "LABELS",
+        conflicts_with = "local",
+        help = "Discover the ranks from the Kubernetes pods matching this label selector instead of --host, \
+                e.g. training.kubeflow.org/job-name=llama. Uses the kubeconfig, or the service account when run in a pod."
+    )]
+    selector: Option<String>,
+
+    /// Kubernetes命名空间
+    #[arg(
+        long = "namespace",
+        value_name = "NAMESPACE",
+        requires = "selector",
+        help = "Namespace of the pods [default: the namespace of the kubeconfig context or service account, or default]."
+    )]
+    namespace: Option<String>,
+
+    /// kubeconfig文件路径
+    #[arg(
+        long = "kubeconfig",
+        value_name = "PATH",
+        requires = "selector",
+        help = "Kubeconfig to reach the API server with [default: $KUBECONFIG or ~/.kube/config, \
+                falling back to the in-cluster service account]."
+    )]
+    kubeconfig: Option<PathBuf>,
+
+    /// kubeconfig中使用的上下文
+    #[arg(long = "context", value_name = "NAME", requires = "selector", help = "Kubeconfig context [default: the current context].")]
+    context: Option<String>,
+
+    /// 记录rank的pod注解
+    #[arg(
+        long = "rank-annotation",
+        value_name = "KEY",
+        default_value = RANK_ANNOTATION,
+        help = "Pod annotation holding the rank of the pod, read before --rank-env."
+    )]
+    rank_annotation: String,
+
+    /// 记录rank的容器环境变量
+    #[arg(
+        long = "rank-env",
+        value_name = "NAME",
+        default_value = RANK_ENV,
+        help = "Container environment variable holding the rank of the pod, as set by the PyTorchJob and MPIJob operators."
+    )]
+    rank_env: String,
+
+    /// 每个pod的进程数
+    #[arg(
+        long = "procs-per-pod",
+        value_name = "N",
+        default_value_t = 1,
+        help = "Number of training processes per pod, listening on consecutive ports from --start-port. \
+                Above 1 the rank of a pod is taken as its node rank, numbering its processes node_rank * N + local_rank."
+    )]
+    procs_per_pod: u32,
+
+    /// 在本机上查找训练进程
+    #[arg(
+        long = "local",
+        help = "Discover the ranks running on this machine instead of --host: processes with a RANK environment variable, \
+                probing the ports they listen on."
+    )]
+    local: bool,
+
+    /// 同时查找各rank的子进程
+    #[arg(
+        long = "workers",
+        requires = "local",
+        help = "Also discover the Probing endpoints of the child processes of each rank, such as DataLoader workers \
+                and checkpoint writers, numbered as workers in PID order."
+    )]
+    workers: bool,
+
+    /// proc文件系统的挂载点
+    #[arg(
+        long = "proc-root",
+        value_name = "PATH",
+        default_value = "/proc",
+        requires = "local",
+        help = "Mount point of the proc file system to read, e.g. the host's /proc mounted into a container."
+    )]
+    proc_root: PathBuf,
+
+    #[arg(
+        long = "timeout-ms",
+        value_name = "MS",
+        help = "How long to wait for each endpoint [default: the collector timeout of the config, or 2000]."
+    )]
+    timeout_ms: Option<u64>,
+}
+
+#[derive(Args, Debug)]
+struct ConfigCommand {
+    #[command(subcommand)]
+    action: ConfigAction,
+}
+
+#[derive(Subcommand, Debug)]
+enum ConfigAction {
+    /// Print the effective configuration merged from the config files and the environment, as TOML.
+    Show,
+}
+
+/// 校验输出目录
+fn parse_output_dir(input_path_str: &str) -> Result<String, String> {
+    let input_path = PathBuf::from(input_path_str);
+    if input_path.is_dir() {
+        return Ok(input_path.to_string_lossy().to_string());
+    }
+    Err("Output path must be a valid directory".to_string())
+}
+
+/// Ranks to collect call stacks from, with their endpoints and nodes.
+#[derive(Debug, Default)]
+struct Targets {
+    rank_list: Vec<u32>,
+    urls: Vec<String>,
+    /// Node (host) of each rank.
+    rank_nodes: BTreeMap<u32, String>,
+    /// Call stack URLs of the worker processes of each rank, with their worker index.
+    workers: BTreeMap<u32, Vec<(u32, String)>>,
+}
+
+/// Returns the path the raw fetched call stacks of a merged stack file are saved to.
+fn raw_data_path(stack_file: &Path) -> PathBuf {
+    stack_file.with_extension("raw.json")
+}
+
+/// Returns the node an `ip:port` address belongs to, i.e. its host part.
+fn node_of_address(address: &str) -> String {
+    let host = match address.rsplit_once(':') {
+        Some((host, port)) if port.chars().all(|c| c.is_ascii_digit()) => host,
+        _ => address,
+    };
+    host.trim_matches(|c| c == '[' || c == ']').to_string()
+}
+
+/// 读取 URL 配置文件
+fn load_url_file(url_file: &str, collector: &CollectorConfig) -> Result<Targets, Box<dyn Error>> {
     let mut file = File::open(url_file)?;
     let mut contents = String::new();
     file.read_to_string(&mut contents)?;
 
     let json: serde_json::Value = serde_json::from_str(&contents)?;
 
-    let mut urls = Vec::new();
-    let mut rank_list = Vec::new();
+    let mut targets = Targets::default();
     if let serde_json::Value::Object(map) = json {
         for (rank_str, value) in map {
             // 提取rank后的数字部分
             let rank_num_str = rank_str.trim_start_matches("rank");
-            
-            // 尝试解析数字部分
-            if let Ok(rank) = rank_num_str.parse::<u32>() {
-                rank_list.push(rank);
-            }
-            
-            if let serde_json::Value::String(address) = value {
-                let new_url = format!("http://{}/apis/pythonext/callstack", address);
-                urls.push(new_url);
+
+            // 地址可以是 "ip:port" 字符串，或 {"address": "ip:port", "node": "名称", "workers": [...]} 对象
+            let (address, node) = match &value {
+                serde_json::Value::String(address) => (Some(address.as_str()), None),
+                serde_json::Value::Object(entry) => (
+                    entry.get("address").and_then(|v| v.as_str()),
+                    entry.get("node").and_then(|v| v.as_str()),
+                ),
+                _ => (None, None),
+            };
+            // rank与地址必须同时有效，否则跳过该项，以免后面的URL对应到错误的rank
+            let (Ok(rank), Some(address)) = (rank_num_str.parse::<u32>(), address) else {
+                eprintln!("Warning: Skipping entry {:?} in {}: expected a rank<N> key with an address", rank_str, url_file);
+                continue;
+            };
+            targets.rank_list.push(rank);
+            targets.urls.push(collector.callstack_url(address));
+            let node = node.map(str::to_string).unwrap_or_else(|| node_of_address(address));
+            targets.rank_nodes.insert(rank, node);
+            // 子进程按worker序号排列，未发现端点的worker为null
+            if let Some(serde_json::Value::Array(workers)) = value.get("workers") {
+                let workers: Vec<(u32, String)> = workers
+                    .iter()
+                    .enumerate()
+                    .filter_map(|(worker, address)| Some((worker as u32, collector.callstack_url(address.as_str()?))))
+                    .collect();
+                if !workers.is_empty() {
+                    targets.workers.insert(rank, workers);
+                }
             }
         }
     }
-    
-    if urls.is_empty() {
+
+    if targets.urls.is_empty() {
         return Err("No valid URLs found in the file".into());
     }
-    
-    println!("Loaded {} URLs from file", urls.len());
-    println!("Ranks parsed: {:?}", rank_list); // 打印解析的rank列表
 
-    let json_data = fetch_stack_from_urls(urls).await?;
-    process_and_merge_callstacks(&json_data, rank_list, output)?;
+    eprintln!("Loaded {} URLs from file", targets.urls.len());
+    eprintln!("Ranks parsed: {:?}", targets.rank_list); // 打印解析的rank列表
+    Ok(targets)
+}
 
-    Ok(())
+/// 解析 -r 参数
+fn parse_rank_args(ranks: &[String], collector: &CollectorConfig) -> Result<Targets, Box<dyn Error>> {
+    let mut targets = Targets::default();
+
+    for (rank, address) in expand_rank_args(ranks)? {
+        targets.rank_list.push(rank);
+        targets.rank_nodes.insert(rank, node_of_address(&address));
+
+        let url = collector.callstack_url(&address);
+        eprintln!("Generated URL: {}", url);
+        targets.urls.push(url);
+    }
+
+    if targets.urls.is_empty() {
+        return Err("No valid URLs generated from -r arguments".into());
+    }
+
+    eprintln!("Parsed ranks: {:?}", targets.rank_list); // 调试输出
+    Ok(targets)
 }
 
-async fn fetch_selected_rankstacks(ranks: Vec<String>, output: Option<&str>) -> Result<(), Box<dyn Error>> {
-    let mut rank_list = Vec::new();
-    let mut urls = Vec::new();
-    
-    for rank_str in ranks {
-        let parts: Vec<&str> = rank_str.splitn(2, ':').collect();
-        
-        if parts.len() == 2 {
-            // 去除排名部分的括号并解析
-            let rank_part = parts[0].trim_matches(|c| c == '<' || c == '>');
-            if let Ok(rank) = rank_part.parse::<u32>() {
-                rank_list.push(rank);
-            } else {
-                eprintln!("Warning: Failed to parse rank from '{}'", parts[0]);
+/// 使用配置文件中的rank地址作为采集目标
+fn targets_from_config(collector: &CollectorConfig, config: &Config) -> Result<Targets, Box<dyn Error>> {
+    let mut targets = Targets::default();
+    for (rank, entry) in config.rank_entries()? {
+        if let Some(address) = &entry.address {
+            targets.rank_list.push(rank);
+            targets.urls.push(collector.callstack_url(address));
+            targets.rank_nodes.insert(rank, node_of_address(address));
+            if !entry.workers.is_empty() {
+                let workers = entry.workers.iter().enumerate();
+                targets.workers.insert(rank, workers.map(|(worker, address)| (worker as u32, collector.callstack_url(address))).collect());
             }
-            
-            // 去除IP:PORT部分的括号
-            let ip_port = parts[1].trim_matches(|c| c == '<' || c == '>');
-            let url = format!("http://{}/apis/pythonext/callstack", ip_port);
-            println!("Generated URL: {}", url);
-            urls.push(url);
-        } else {
-            eprintln!("Warning: Invalid format '{}', expected '<rank>:<ip:port>'", rank_str);
         }
     }
+    if targets.urls.is_empty() {
+        return Err("No ranks to collect from, use -f/--file, -r/--rank or rank addresses in the config file".into());
+    }
+    eprintln!("Ranks from config: {:?}", targets.rank_list);
+    Ok(targets)
+}
+
+/// Reads a raw call stack file; its stacks belong to `rank_list`, or to ranks 0, 1, 2, ... when empty.
+fn load_raw_file(path: &Path, rank_list: Vec<u32>) -> Result<(String, Vec<u32>), Box<dyn Error>> {
+    let json_data = std::fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read raw call stacks {}: {}", path.display(), e))?;
+    let rank_list = if rank_list.is_empty() {
+        let count = serde_json::from_str::<Vec<serde_json::Value>>(&json_data)?.len() as u32;
+        (0..count).collect()
+    } else {
+        rank_list
+    };
+    Ok((json_data, rank_list))
+}
+
+/// 一次采集的结果
+struct Fetched {
+    json_data: String,
+    /// Ranks of the fetched stacks, in the order of `json_data`, leaving out the failed targets.
+    rank_list: Vec<u32>,
+    processes: BTreeMap<u32, ProcessInfo>,
+}
+
+/// 并发采集各rank的调用栈及进程信息
+async fn fetch_targets(targets: &Targets, collector: &CollectorConfig) -> Result<Fetched, Box<dyn Error>> {
+    let (stacks, processes) = futures::join!(
+        fetch_stack_from_urls(targets.urls.clone(), collector),
+        fetch_process_info(&targets.urls, collector)
+    );
+    let (json_data, fetched) = stacks?;
+    // 失败的rank不能占用后续调用栈的位置
+    let rank_list: Vec<u32> = fetched.iter().filter_map(|index| targets.rank_list.get(*index).copied()).collect();
+    let json_data = fetch_workers(json_data, &rank_list, &targets.workers, collector).await?;
+    let processes = targets
+        .rank_list
+        .iter()
+        .zip(processes)
+        .filter_map(|(rank, info)| Some((*rank, info?)))
+        .collect();
+    Ok(Fetched { json_data, rank_list, processes })
+}
+
+/// 采集各rank子进程（DataLoader worker等）的调用栈，并放入所属rank的调用栈中
+async fn fetch_workers(
+    json_data: String,
+    rank_list: &[u32],
+    workers: &BTreeMap<u32, Vec<(u32, String)>>,
+    collector: &CollectorConfig,
+) -> Result<String, Box<dyn Error>> {
+    let owners: Vec<(u32, u32)> = rank_list
+        .iter()
+        .flat_map(|rank| workers.get(rank).into_iter().flatten().map(|(worker, _)| (*rank, *worker)))
+        .collect();
+    if owners.is_empty() {
+        return Ok(json_data);
+    }
+    let urls = rank_list.iter().flat_map(|rank| workers.get(rank).into_iter().flatten().map(|(_, url)| url.clone()));
+    let (worker_data, fetched) = fetch_stack_from_urls(urls.collect(), collector).await?;
+    let worker_stacks: Vec<serde_json::Value> = serde_json::from_str(&worker_data)?;
+    let mut by_rank: BTreeMap<u32, Vec<(u32, serde_json::Value)>> = BTreeMap::new();
+    for (index, stacks) in fetched.into_iter().zip(worker_stacks) {
+        let (rank, worker) = owners[index];
+        by_rank.entry(rank).or_default().push((worker, stacks));
+    }
+    eprintln!("Fetched the call stacks of {} of {} worker processes", by_rank.values().map(Vec::len).sum::<usize>(), owners.len());
+
+    let stacks: Vec<serde_json::Value> = serde_json::from_str(&json_data)?;
+    let stacks: Vec<serde_json::Value> = stacks
+        .into_iter()
+        .zip(rank_list)
+        .map(|(stacks, rank)| attach_workers(stacks, by_rank.remove(rank).unwrap_or_default()))
+        .collect();
+    Ok(serde_json::to_string_pretty(&stacks)?)
+}
+
+/// 校验各rank的进程报告的RANK与配置一致，不一致时除非指定--force否则拒绝合并
+fn verify_rank_identity(
+    rank_list: &[u32],
+    processes: &BTreeMap<u32, ProcessInfo>,
+    force: bool,
+) -> Result<(), Box<dyn Error>> {
+    let check = IdentityCheck::new(rank_list, processes);
+    if check.is_consistent() {
+        if !check.unverified.is_empty() && check.unverified.len() < rank_list.len() {
+            eprint!("Warning: {}", check);
+        }
+        return Ok(());
+    }
+    eprint!("Rank identity mismatch:\n{}", check);
+    if !force {
+        return Err("The call stacks do not come from the configured ranks; \
+                    update the targets, or pass --force to merge them anyway"
+            .into());
+    }
+    eprintln!("Warning: Merging anyway because of --force");
+    Ok(())
+}
+
+/// Reads the process metadata saved next to a raw call stack file, if any.
+fn load_processes(raw_file: &Path) -> Result<BTreeMap<u32, ProcessInfo>, Box<dyn Error>> {
+    let path = processes_path(raw_file);
+    if !path.is_file() {
+        return Ok(BTreeMap::new());
+    }
+    let content = std::fs::read_to_string(&path)?;
+    serde_json::from_str(&content).map_err(|e| format!("Invalid process metadata {}: {}", path.display(), e).into())
+}
+
+/// Local time raw call stacks were captured: the start of the run that wrote them, from the
+/// `manifest.json` next to them, else the modification time of the file.
+fn raw_captured_at(raw_file: &Path) -> Option<String> {
+    let manifest = raw_file.parent().and_then(|dir| std::fs::read_to_string(dir.join(MANIFEST)).ok());
+    if let Some(manifest) = manifest.and_then(|content| serde_json::from_str::<Manifest>(&content).ok()) {
+        return Some(manifest.started_at);
+    }
+    let modified = std::fs::metadata(raw_file).and_then(|metadata| metadata.modified()).ok()?;
+    Some(chrono::DateTime::<Local>::from(modified).format("%Y-%m-%d %H:%M:%S").to_string())
+}
+
+/// Records the collection targets in the manifest of a run.
+fn record_targets(run: &mut RunDir, args: &TargetArgs, targets: &Targets) {
+    if args.ranks.is_empty()
+        && let Some(file) = &args.fetch_file
+    {
+        run.manifest.inputs.insert("url_file".to_string(), PathBuf::from(file));
+    }
+    run.manifest.ranks = targets.rank_list.clone();
+    run.manifest.targets = targets.rank_list.iter().cloned().zip(targets.urls.iter().cloned()).collect();
+}
+
+/// 采集调用栈并保存原始数据
+async fn run_collect(command: CollectCommand, run: &mut RunDir, config: &Config) -> Result<(), Box<dyn Error>> {
+    let targets = command.targets.resolve(config)?;
+    record_targets(run, &command.targets, &targets);
+    run.manifest.set_settings("collector", &config.collector)?;
+    let fetched = fetch_targets(&targets, &config.collector).await?;
+    run.manifest.ranks = fetched.rank_list.clone();
+    // 只记录不一致，合并时再决定是否拒绝
+    let check = IdentityCheck::new(&fetched.rank_list, &fetched.processes);
+    if !check.is_consistent() {
+        eprint!("Warning: Rank identity mismatch:\n{}", check);
+    }
+
+    let timestamp = Local::now().format("%Y%m%d%H%M%S").to_string();
+    let raw_path = run.path().join(format!("callstacks_{}.raw.json", timestamp));
+    std::fs::write(&raw_path, &fetched.json_data)?;
+    if !fetched.processes.is_empty() {
+        std::fs::write(processes_path(&raw_path), serde_json::to_string_pretty(&fetched.processes)?)?;
+    }
+
+    let ranks: Vec<String> = fetched.rank_list.iter().map(u32::to_string).collect();
+    println!("Raw call stacks saved as {}", raw_path.display());
+    println!("Merge them with: flame merge --raw {} --rank-list {}", raw_path.display(), ranks.join(","));
+    Ok(())
+}
+
+/// 导入py-spy、GDB或perf script的堆栈文件，保存为原始调用栈
+fn run_import(command: ImportCommand, run: &mut RunDir) -> Result<(), Box<dyn Error>> {
+    if !command.rank_list.is_empty() && command.rank_list.len() != command.files.len() {
+        return Err(format!("--rank-list has {} ranks for {} files", command.rank_list.len(), command.files.len()).into());
+    }
+    let mut dumps = BTreeMap::new();
+    for (index, file) in command.files.iter().enumerate() {
+        let rank = match command.rank_list.get(index) {
+            Some(rank) => *rank,
+            None => rank_from_file_name(file)?,
+        };
+        let content = std::fs::read_to_string(file).map_err(|e| format!("Failed to read {}: {}", file.display(), e))?;
+        let stacks = import_dump(&content, command.format).map_err(|e| format!("{}: {}", file.display(), e))?;
+        if dumps.insert(rank, stacks).is_some() {
+            return Err(format!("Rank {} is given by several files", rank).into());
+        }
+        run.manifest.inputs.insert(format!("rank{}_dump", rank), file.clone());
+    }
 
+    let rank_list: Vec<u32> = dumps.keys().copied().collect();
+    let stacks: Vec<_> = dumps.into_values().collect();
+    run.manifest.ranks = rank_list.clone();
+    let timestamp = Local::now().format("%Y%m%d%H%M%S").to_string();
+    let raw_path = run.path().join(format!("callstacks_{}.raw.json", timestamp));
+    std::fs::write(&raw_path, serde_json::to_string_pretty(&stacks)?)?;
+
+    let ranks: Vec<String> = rank_list.iter().map(u32::to_string).collect();
+    println!("Imported the call stacks of ranks {}", format_rank_ranges(&rank_list));
+    println!("Raw call stacks saved as {}", raw_path.display());
+    println!("Merge them with: flame merge --raw {} --rank-list {}", raw_path.display(), ranks.join(","));
+    Ok(())
+}
+
+/// 合并调用栈，可选地直接绘制火焰图
+async fn run_merge(command: MergeCommand, run: &mut RunDir, config: &Config) -> Result<(), Box<dyn Error>> {
+    let force = command.merge.force;
+    let mut merge_options = command.merge.options(config);
+    let (json_data, rank_list) = match &command.raw {
+        Some(raw) => {
+            run.manifest.inputs.insert("raw_callstacks".to_string(), raw.clone());
+            merge_options.processes = load_processes(raw)?;
+            merge_options.captured_at = raw_captured_at(raw);
+            load_raw_file(raw, command.rank_list)?
+        }
+        None => {
+            let targets = command.targets.resolve(config)?;
+            record_targets(run, &command.targets, &targets);
+            run.manifest.set_settings("collector", &config.collector)?;
+            let fetched = fetch_targets(&targets, &config.collector).await?;
+            merge_options.processes = fetched.processes;
+            merge_options.rank_nodes = targets.rank_nodes;
+            (fetched.json_data, fetched.rank_list)
+        }
+    };
+    run.manifest.ranks = rank_list.clone();
+    verify_rank_identity(&rank_list, &merge_options.processes, force)?;
+    run.manifest.set_settings("merge", &merge_options)?;
+
+    let stack_file = process_and_merge_callstacks(&json_data, rank_list, run.path(), &merge_options)?;
+    // 采集到的原始数据默认随合并结果写入运行目录；--raw 的输入文件已记录在manifest中，不再复制
+    if command.raw.is_none() && !command.pipeline.no_raw {
+        std::fs::write(raw_data_path(&stack_file), &json_data)?;
+    }
+    if command.raw.is_some() {
+        println!("Saved call stacks have been processed and merged successfully");
+    } else {
+        println!("Call stacks have been collected, processed, and merged successfully");
+    }
+
+    if !command.pipeline.pipeline {
+        return Ok(());
+    }
+
+    command.draw.draw(&stack_file.to_string_lossy(), run, config)?;
+    if command.pipeline.discard_intermediate {
+        std::fs::remove_file(&stack_file)?;
+        std::fs::remove_file(FrameMeta::sidecar_path(&stack_file))?;
+    }
+    Ok(())
+}
+
+/// 分析调用栈：按调用栈对rank分组，并输出训练步偏差与局部变量报告
+async fn run_analyze(command: AnalyzeCommand, config: &Config) -> Result<(), Box<dyn Error>> {
+    let force = command.merge.force;
+    let mut merge_options = command.merge.options(config);
+    let (json_data, rank_list) = match &command.raw {
+        Some(raw) => {
+            merge_options.processes = load_processes(raw)?;
+            load_raw_file(raw, command.rank_list)?
+        }
+        None => {
+            let targets = command.targets.resolve(config)?;
+            let fetched = fetch_targets(&targets, &config.collector).await?;
+            merge_options.processes = fetched.processes;
+            merge_options.rank_nodes = targets.rank_nodes;
+            (fetched.json_data, fetched.rank_list)
+        }
+    };
+    verify_rank_identity(&rank_list, &merge_options.processes, force)?;
+
+    let merged = merge_callstacks(&json_data, rank_list, &merge_options)?;
+    let mut stdout = std::io::stdout();
+    merged.write_stack_groups(&mut stdout)?;
+    let meta = merged.frame_meta(&merge_options);
+    if let Some(report) = merged.report(&meta, &merge_options)? {
+        println!();
+        print!("{}", report);
+    }
+    Ok(())
+}
+
+/// 探测各节点上的 Probing 端口并生成 URL 配置文件
+async fn run_discover(command: DiscoverCommand, output: Option<&Path>, config: &Config) -> Result<(), Box<dyn Error>> {
+    let timeout = command
+        .timeout_ms
+        .map(Duration::from_millis)
+        .or(config.collector.timeout())
+        .unwrap_or(Duration::from_millis(2000));
+
+    let urls = if command.local {
+        discover_local(&command, &config.collector, timeout).await?
+    } else {
+        discover_remote(&command, &config.collector, timeout).await?
+    };
     if urls.is_empty() {
-        return Err("No valid URLs generated from -r arguments".into());
+        return Err("No Probing endpoint found".into());
+    }
+
+    let json = serde_json::to_string_pretty(&serde_json::Value::Object(urls))?;
+    match output {
+        Some(dir) => {
+            let path = dir.join("urls.json");
+            std::fs::write(&path, json)?;
+            println!("URL file saved as {}", path.display());
+        }
+        None => println!("{}", json),
+    }
+    Ok(())
+}
+
+/// 探测给定节点或Kubernetes pod上的 Probing 端口
+async fn discover_remote(
+    command: &DiscoverCommand,
+    collector: &CollectorConfig,
+    timeout: Duration,
+) -> Result<serde_json::Map<String, serde_json::Value>, Box<dyn Error>> {
+    // 候选地址：(rank, ip:port, 节点)
+    let mut candidates = Vec::new();
+    if let Some(selector) = &command.selector {
+        for target in discover_pods(command, selector).await? {
+            candidates.push((target.rank, target.address, target.node));
+        }
+    }
+    for (host_index, host) in command.hosts.iter().enumerate() {
+        for offset in 0..command.procs_per_host {
+            let port = command.start_port.checked_add(offset).ok_or("Port number out of range")?;
+            let rank = host_index as u32 * command.procs_per_host as u32 + offset as u32;
+            let address = if host.contains(':') && !host.starts_with('[') { format!("[{}]:{}", host, port) } else { format!("{}:{}", host, port) };
+            candidates.push((rank, address, None));
+        }
     }
 
-    println!("Parsed ranks: {:?}", rank_list); // 调试输出
-    
-    let json_data = fetch_stack_from_urls(urls).await?;
-    process_and_merge_callstacks(&json_data, rank_list, output)?;
+    let addresses: Vec<String> = candidates.iter().map(|(_, address, _)| address.clone()).collect();
+    let reachable = probe_endpoints(&addresses, collector, timeout).await;
 
+    let mut urls = serde_json::Map::new();
+    for ((rank, address, node), ok) in candidates.into_iter().zip(reachable) {
+        if ok {
+            let entry = match node {
+                Some(node) => serde_json::json!({"address": address, "node": node}),
+                None => serde_json::Value::String(address),
+            };
+            urls.insert(format!("rank{}", rank), entry);
+        } else {
+            eprintln!("Warning: No Probing endpoint at {} (rank {})", address, rank);
+        }
+    }
+    eprintln!("Found {} of {} endpoints", urls.len(), addresses.len());
+    Ok(urls)
+}
+
+/// 在本机/proc中查找训练进程及其子进程，并探测它们监听的端口
+async fn discover_local(
+    command: &DiscoverCommand,
+    collector: &CollectorConfig,
+    timeout: Duration,
+) -> Result<serde_json::Map<String, serde_json::Value>, Box<dyn Error>> {
+    let proc_root = command.proc_root.as_path();
+    let ranks = local_ranks(proc_root).map_err(|e| format!("Failed to read {}: {}", proc_root.display(), e))?;
+    if ranks.is_empty() {
+        return Err(format!("No process with a RANK environment variable found in {}", proc_root.display()).into());
+    }
+    let node = std::fs::read_to_string(proc_root.join("sys/kernel/hostname"))
+        .map(|hostname| hostname.trim().to_string())
+        .ok()
+        .filter(|hostname| !hostname.is_empty());
+
+    // 进程：(rank, worker序号, pid)
+    let mut processes = Vec::new();
+    for local in &ranks {
+        processes.push((local.rank, None, local.pid));
+        if command.workers {
+            processes.extend(local.workers.iter().enumerate().map(|(worker, pid)| (local.rank, Some(worker as u32), *pid)));
+        }
+    }
+
+    // 候选地址：(进程序号, ip:port)
+    let mut candidates = Vec::new();
+    for (index, (rank, worker, pid)) in processes.iter().enumerate() {
+        let mut addresses = listening_addresses(proc_root, *pid);
+        // fork出的子进程继承父进程的监听socket，TCPStore的MASTER_PORT也不是Probing端口
+        if worker.is_some()
+            && let Some(local) = ranks.iter().find(|local| local.rank == *rank)
+        {
+            let inherited = listening_addresses(proc_root, local.pid);
+            addresses.retain(|address| !inherited.contains(address));
+        }
+        let master_port = read_environ(proc_root, *pid).get("MASTER_PORT").and_then(|port| port.parse::<u16>().ok());
+        addresses.retain(|address| Some(address.port()) != master_port);
+        for address in addresses {
+            // 监听所有地址的端口用主机名访问，以便从其他节点采集
+            let address = match &node {
+                Some(node) if address.ip().is_unspecified() => format!("{}:{}", node, address.port()),
+                None if address.ip().is_unspecified() => format!("127.0.0.1:{}", address.port()),
+                _ => address.to_string(),
+            };
+            candidates.push((index, address));
+        }
+    }
+    let addresses: Vec<String> = candidates.iter().map(|(_, address)| address.clone()).collect();
+    let reachable = probe_endpoints(&addresses, collector, timeout).await;
+
+    // 每个进程取第一个可访问的端口
+    let mut found: BTreeMap<usize, String> = BTreeMap::new();
+    for ((index, address), ok) in candidates.into_iter().zip(reachable) {
+        if ok {
+            found.entry(index).or_insert(address);
+        }
+    }
+
+    let mut urls = serde_json::Map::new();
+    for local in &ranks {
+        let endpoint = |worker: Option<u32>| {
+            let index = processes.iter().position(|(rank, w, _)| *rank == local.rank && *w == worker)?;
+            found.get(&index).cloned()
+        };
+        let Some(address) = endpoint(None) else {
+            eprintln!("Warning: No Probing endpoint for rank {} (pid {})", local.rank, local.pid);
+            continue;
+        };
+        let mut entry = serde_json::json!({"address": address});
+        if let Some(node) = &node {
+            entry["node"] = serde_json::Value::from(node.clone());
+        }
+        if command.workers && !local.workers.is_empty() {
+            let workers: Vec<Option<String>> = (0..local.workers.len() as u32).map(|worker| endpoint(Some(worker))).collect();
+            let missing = workers.iter().filter(|address| address.is_none()).count();
+            if missing > 0 {
+                eprintln!("Warning: No Probing endpoint for {} of {} workers of rank {}", missing, workers.len(), local.rank);
+            }
+            entry["workers"] = serde_json::json!(workers);
+        }
+        urls.insert(format!("rank{}", local.rank), entry);
+    }
+    eprintln!("Found {} of {} ranks", urls.len(), ranks.len());
+    Ok(urls)
+}
+
+/// 检查各rank的Probing端点
+async fn run_check(command: CheckCommand, config: &Config) -> Result<(), Box<dyn Error>> {
+    let targets = command.targets.resolve(config)?;
+    let timeout = command
+        .timeout_ms
+        .map(Duration::from_millis)
+        .or(config.collector.timeout())
+        .unwrap_or(Duration::from_millis(5000));
+    // 每个目标的rank与URL成对加入，按对应关系给每个端点标上rank
+    let ranks = targets.rank_list.into_iter().map(Some).zip(targets.urls).collect();
+    let results = check_endpoints(ranks, &config.collector, timeout).await?;
+
+    if command.json {
+        println!("{}", serde_json::to_string_pretty(&results)?);
+    } else {
+        print!("{}", health_table(&results));
+    }
+    let failed = results.iter().filter(|health| !health.is_ok()).count();
+    if failed > 0 {
+        return Err(format!("{} of {} endpoints failed the check", failed, results.len()).into());
+    }
     Ok(())
 }
 
+/// 通过Kubernetes API查找训练pod及其rank
+async fn discover_pods(command: &DiscoverCommand, selector: &str) -> Result<Vec<PodTarget>, Box<dyn Error>> {
+    let kubeconfig = command.kubeconfig.clone().or_else(KubeAccess::default_kubeconfig);
+    let access = match kubeconfig {
+        Some(path) if command.kubeconfig.is_some() || path.is_file() => {
+            KubeAccess::from_kubeconfig(&path, command.context.as_deref())?
+        }
+        _ => KubeAccess::in_cluster()?.ok_or("No kubeconfig found and not running in a Kubernetes pod, pass --kubeconfig")?,
+    };
+    let namespace = command
+        .namespace
+        .clone()
+        .or_else(|| access.namespace.clone())
+        .unwrap_or_else(|| "default".to_string());
+
+    let pods = access.list_pods(&namespace, selector, Duration::from_secs(30)).await?;
+    println!("Found {} pods matching '{}' in namespace {}", pods.len(), selector, namespace);
+    let options = PodRankOptions {
+        rank_annotation: command.rank_annotation.clone(),
+        rank_env: command.rank_env.clone(),
+        procs_per_pod: command.procs_per_pod,
+        base_port: command.start_port,
+    };
+    Ok(pod_targets(&pods, &options)?)
+}
+
 /// 解析命令行并调用相应函数
 pub async fn run_cli() -> Result<(), Box<dyn Error>> {
-    let cli = Cli::parse();
+    let cli = Cli::from_arg_matches(&build_cli().get_matches())?;
+    let (config, config_sources) = Config::load(cli.config.as_deref())?;
+    // -o 优先于配置文件中的输出目录
+    let output = cli.output.as_ref().map(PathBuf::from).or_else(|| config.output.clone());
+    let run_base = output.clone().unwrap_or_else(RunDir::default_base);
 
-    match (cli.draw_input, cli.fetch_file, !cli.ranks.is_empty()) {
-        (Some(input), _, _) => {
-            draw_frame_graph(&input, cli.output.as_deref());
-            println!("Frame graph has been drawn successfully");
+    // 不带子命令时，将旧的 -i/-f/-r 参数映射为对应子命令
+    let command = match cli.command {
+        Some(command) => command,
+        None => match cli.draw_input {
+            Some(input) => Commands::Draw(DrawCommand { input, draw: cli.draw }),
+            None if cli.targets.is_set() => Commands::Merge(Box::new(MergeCommand {
+                targets: cli.targets,
+                raw: None,
+                rank_list: Vec::new(),
+                merge: cli.merge,
+                pipeline: cli.pipeline,
+                draw: cli.draw,
+            })),
+            None => {
+                // 如果没有提供任何选项，显示帮助信息
+                eprintln!("Error: You must specify a subcommand, or either -i/--input, -f/--file, -r/--rank, --nodelist or --hostfile option.");
+                eprintln!("Run `flame --help` for usage information.");
+                std::process::exit(1);
+            }
+        },
+    };
+
+    // 采集、合并、绘制的结果统一写入本次运行的目录
+    let mut run = match &command {
+        Commands::Collect(_) | Commands::Import(_) | Commands::Merge(_) | Commands::Draw(_) => Some(RunDir::create(&run_base)?),
+        _ => None,
+    };
+
+    let result = match command {
+        Commands::Collect(command) => run_collect(command, run.as_mut().expect("run directory"), &config).await,
+        Commands::Import(command) => run_import(command, run.as_mut().expect("run directory")),
+        Commands::Merge(command) => run_merge(*command, run.as_mut().expect("run directory"), &config).await,
+        Commands::Draw(command) => {
+            let run = run.as_mut().expect("run directory");
+            run.manifest.inputs.insert("stack_file".to_string(), PathBuf::from(&command.input));
+            command.draw.draw(&command.input, run, &config).map(|_| ())
         }
-        (_, Some(file), false) => {
-            // 仅使用 -f 参数，原有从文件读取 URL 的逻辑
-            fetch_process_and_merge(&file, cli.output.as_deref()).await?;
-            println!("Call stacks have been collected, processed, and merged successfully");
+        Commands::Analyze(command) => run_analyze(command, &config).await,
+        Commands::Diff(command) => {
+            let diff = diff_stack_files(&command.before, &command.after)?;
+            print!("{}", diff);
+            Ok(())
         }
-        (_, _, true) => {
-            // 仅使用 -r 参数
-            fetch_selected_rankstacks(cli.ranks, cli.output.as_deref()).await?;
-            println!("Call stacks have been collected, processed, and merged successfully");
+        Commands::Check(command) => run_check(command, &config).await,
+        Commands::Discover(command) => run_discover(command, output.as_deref(), &config).await,
+        Commands::Config(ConfigCommand { action: ConfigAction::Show }) => {
+            if config_sources.is_empty() {
+                println!("# No config file found");
+            }
+            for path in &config_sources {
+                println!("# Loaded from {}", path.display());
+            }
+            print!("{}", toml::to_string_pretty(&config)?);
+            Ok(())
         }
-        _ => {
-            // 如果没有提供任何选项，显示帮助信息
-            eprintln!("Error: You must specify either -i/--input, -f/--file, or -r/--rank option.");
-            eprintln!("Run `flame --help` for usage information.");
-            std::process::exit(1);
+    };
+
+    // 失败时也写入清单，记录已生成的文件；清单写入失败不能掩盖命令本身的错误
+    if let Some(run) = run
+        && let Err(e) = run.finish()
+    {
+        if result.is_ok() {
+            return Err(e);
         }
+        eprintln!("Warning: Failed to write the run manifest: {}", e);
     }
+    result
+}
 
-    Ok(())
-}
\ No newline at end of file
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::CommandFactory;
+
+    /// The subcommands and the legacy top-level flags both parse.
+    #[test]
+    fn test_subcommands_and_legacy_flags() {
+        Cli::command().debug_assert();
+
+        let cli = Cli::try_parse_from(["flame", "-r", "0:10.0.0.5:11490", "--draw", "--palette", "coverage"])
+            .expect("Legacy flags should parse");
+        assert!(cli.command.is_none());
+        assert_eq!(cli.targets.ranks, vec!["0:10.0.0.5:11490".to_string()]);
+        assert!(cli.pipeline.pipeline);
+
+        let cli = Cli::try_parse_from(["flame", "merge", "--raw", "stacks.raw.json", "--rank-list", "4,5", "--icicle"])
+            .expect("Merge subcommand should parse");
+        match cli.command {
+            Some(Commands::Merge(command)) => {
+                assert_eq!(command.rank_list, vec![4, 5]);
+                assert!(command.merge.icicle);
+            }
+            other => panic!("unexpected command {:?}", other),
+        }
+
+        assert!(Cli::try_parse_from(["flame", "-i", "stacks.txt", "draw", "-i", "stacks.txt"]).is_err());
+        assert!(Cli::try_parse_from(["flame", "merge", "--raw", "a.json", "-f", "urls.json"]).is_err());
+        assert!(Cli::try_parse_from(["flame", "collect", "--nodelist", "gpu[1-2]", "--hostfile", "hosts"]).is_err());
+    }
+
+    /// Raw call stacks are dated by the run that collected them, else by their file.
+    #[test]
+    fn test_raw_captured_at() {
+        let dir = std::env::temp_dir().join(format!("flame_test_captured_at_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).expect("Failed to create test directory");
+        let raw = dir.join("callstacks_20261018090000.raw.json");
+        std::fs::write(&raw, "[]").expect("Failed to write raw file");
+        assert!(raw_captured_at(&raw).is_some_and(|captured_at| captured_at.starts_with("20")));
+
+        let manifest = Manifest { started_at: "2026-10-18 09:00:00".to_string(), ..Default::default() };
+        std::fs::write(dir.join(MANIFEST), serde_json::to_string(&manifest).expect("Failed to serialize manifest"))
+            .expect("Failed to write manifest");
+        assert_eq!(raw_captured_at(&raw).as_deref(), Some("2026-10-18 09:00:00"));
+    }
+
+    /// Entries without a rank or an address are skipped so later URLs keep their own rank.
+    #[test]
+    fn test_load_url_file_skips_invalid_entries() {
+        let path = std::env::temp_dir().join(format!("flame_test_url_file_{}.json", std::process::id()));
+        std::fs::write(&path, r#"{"rank0":"127.0.0.1:1","rank1":null,"rank2":"127.0.0.1:3","rank3":{"node":"n1"},"x":"127.0.0.1:5"}"#)
+            .expect("Failed to write URL file");
+        let targets = load_url_file(path.to_str().expect("Invalid path"), &CollectorConfig::default())
+            .expect("Failed to load URL file");
+        assert_eq!(targets.rank_list, vec![0, 2]);
+        assert_eq!(targets.urls.len(), 2);
+        assert!(targets.urls[1].starts_with("http://127.0.0.1:3/"));
+    }
+}
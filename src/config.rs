@@ -0,0 +1,179 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::path::{Path, PathBuf};
+
+use crate::collector::CollectorConfig;
+use crate::draw_flame::{DrawOptions, FramePalette};
+use crate::process::FrameFilter;
+
+/// Name of the project config file looked up in the working directory.
+pub const PROJECT_CONFIG: &str = "flame.toml";
+
+/// Metadata of one rank set in the config file.
+#[derive(Debug, Default, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RankConfig {
+    /// `ip:port` of the rank's Probing endpoint, used when no -f/-r target is given.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub address: Option<String>,
+    /// Node the rank runs on, overriding the host of its address.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub node: Option<String>,
+    /// `ip:port` of the Probing endpoints of the rank's worker processes, by worker index.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub workers: Vec<String>,
+}
+
+/// Settings read from the config files and `FLAME_*` environment variables.
+/// Command-line flags take precedence over all of them.
+#[derive(Debug, Default, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Base directory of the run directories, used when -o is not given.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub output: Option<PathBuf>,
+    pub collector: CollectorConfig,
+    pub filter: FrameFilter,
+    /// Rank metadata keyed by rank number, e.g. `[ranks.0]` or `[ranks.rank0]`.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub ranks: BTreeMap<String, RankConfig>,
+    pub draw: DrawOptions,
+}
+
+impl Config {
+    /// Fills the unset values of `self` from `base`. Rule lists replace those of `base`
+    /// and rank entries replace the entry of the same rank.
+    pub fn or(self, base: Config) -> Config {
+        let mut ranks = base.ranks;
+        ranks.extend(self.ranks);
+        Config {
+            output: self.output.or(base.output),
+            collector: self.collector.or(base.collector),
+            filter: self.filter.or(base.filter),
+            ranks,
+            draw: self.draw.or(base.draw),
+        }
+    }
+
+    pub fn from_file(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read config file {}: {}", path.display(), e))?;
+        toml::from_str(&content).map_err(|e| format!("Invalid config file {}: {}", path.display(), e).into())
+    }
+
+    /// Reads the `FLAME_*` overrides through `var`, e.g. `std::env::var`.
+    pub fn from_env(var: impl Fn(&str) -> Option<String>) -> Result<Self, String> {
+        let mut config = Config {
+            output: var("FLAME_OUTPUT").map(PathBuf::from),
+            ..Default::default()
+        };
+        config.collector.endpoint_path = var("FLAME_ENDPOINT_PATH");
+        if let Some(timeout) = var("FLAME_TIMEOUT_SECS") {
+            let timeout = timeout.parse().map_err(|e| format!("Invalid FLAME_TIMEOUT_SECS '{}': {}", timeout, e))?;
+            config.collector.timeout_secs = Some(timeout);
+        }
+        if let Some(palette) = var("FLAME_PALETTE") {
+            let palette = <FramePalette as clap::ValueEnum>::from_str(&palette, true)
+                .map_err(|e| format!("Invalid FLAME_PALETTE '{}': {}", palette, e))?;
+            config.draw.palette = Some(palette);
+        }
+        config.draw.job_name = var("FLAME_JOB_NAME");
+        config.draw.title = var("FLAME_TITLE");
+        Ok(config)
+    }
+
+    /// Config files in increasing precedence: `$XDG_CONFIG_HOME/flame/config.toml`
+    /// (or `~/.config/flame/config.toml`), then `flame.toml` in the working directory.
+    pub fn search_paths() -> Vec<PathBuf> {
+        let mut paths = Vec::new();
+        let config_home = std::env::var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")));
+        if let Some(config_home) = config_home {
+            paths.push(config_home.join("flame").join("config.toml"));
+        }
+        paths.push(PathBuf::from(PROJECT_CONFIG));
+        paths
+    }
+
+    /// Loads the effective configuration: the config files found in the search paths, the file given
+    /// by `--config`, then the environment. Returns it with the files it was read from.
+    pub fn load(explicit: Option<&Path>) -> Result<(Config, Vec<PathBuf>), Box<dyn Error>> {
+        let mut config = Config::default();
+        let mut sources = Vec::new();
+        for path in Config::search_paths() {
+            if path.is_file() {
+                config = Config::from_file(&path)?.or(config);
+                sources.push(path);
+            }
+        }
+        if let Some(path) = explicit {
+            config = Config::from_file(path)?.or(config);
+            sources.push(path.to_path_buf());
+        }
+        config = Config::from_env(|name| std::env::var(name).ok())?.or(config);
+        Ok((config, sources))
+    }
+
+    /// Rank metadata by rank number; keys may be written as `0` or `rank0`.
+    pub fn rank_entries(&self) -> Result<BTreeMap<u32, &RankConfig>, String> {
+        self.ranks
+            .iter()
+            .map(|(key, entry)| {
+                key.trim_start_matches("rank")
+                    .parse::<u32>()
+                    .map(|rank| (rank, entry))
+                    .map_err(|_| format!("Invalid rank '{}' in the config file", key))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    /// Environment overrides win over the config file, which fills everything else.
+    #[test]
+    fn test_config_layering() {
+        let file: Config = toml::from_str(
+            r#"
+            output = "/data/flame"
+
+            [collector]
+            endpoint_path = "/apis/pythonext/callstack"
+            timeout_secs = 10
+
+            [filter]
+            exclude = ["^_PyEval_"]
+
+            [ranks.0]
+            node = "gpu001"
+
+            [ranks.rank1]
+            address = "10.0.0.5:11491"
+
+            [draw]
+            palette = "coverage"
+            title = "from file"
+            "#,
+        )
+        .expect("Invalid config");
+        let env = HashMap::from([("FLAME_TIMEOUT_SECS", "30"), ("FLAME_TITLE", "from env")]);
+        let env = Config::from_env(|name| env.get(name).map(|value| value.to_string())).expect("Invalid environment");
+
+        let config = env.or(file);
+        assert_eq!(config.collector.timeout_secs, Some(30));
+        assert_eq!(config.draw.title.as_deref(), Some("from env"));
+        assert_eq!(config.draw.palette, Some(FramePalette::Coverage));
+        assert_eq!(config.filter.exclude, vec!["^_PyEval_".to_string()]);
+        let ranks = config.rank_entries().expect("Invalid ranks");
+        assert_eq!(ranks[&0].node.as_deref(), Some("gpu001"));
+        assert_eq!(ranks[&1].address.as_deref(), Some("10.0.0.5:11491"));
+
+        let shown = toml::to_string_pretty(&config).expect("Failed to serialize config");
+        assert_eq!(toml::from_str::<Config>(&shown).expect("Shown config should parse back"), config);
+    }
+}
@@ -1,68 +1,602 @@
 use std::fs::File;
-use std::io::BufReader;
-use inferno::flamegraph::{self, Options, Palette};
-use std::path::PathBuf;
-use chrono::Local;
-use std::env;
-
-/// Generates a flamegraph from a stack trace file and saves it as an SVG file.
-/// If `output_path` is `None`, the SVG file will be saved in the 'flame_svg' directory 
-/// at the same level as the parent directory of the input file.
-/// If `output_path` is `Some`, the SVG file will be saved in the specified directory.
-pub fn draw_frame_graph(file_path: &str, output_path: Option<&str>) {
-    // Open the input file containing stack trace data
-    let file = File::open(file_path).expect("Failed to open file");
-    // Wrap the file in a BufReader for efficient reading
-    let reader = BufReader::new(file);
+use std::io::Write;
+use inferno::flamegraph::{self, color::Color, color::PaletteMap, color::SearchColor, Direction, Options, Palette};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+
+use crate::frame_meta::{FrameCategory, FrameInfo, FrameMeta};
+use crate::process::format_rank_ranges;
+
+/// Errors raised while drawing flamegraphs from a merged stack file.
+#[derive(Debug, thiserror::Error)]
+pub enum DrawError {
+    #[error("failed to read stack file {path}: {source}")]
+    ReadInput {
+        path: PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
+    #[error("stack file {0} has no usable file name")]
+    InvalidFileName(PathBuf),
+    #[error("{path} has {} malformed line(s), expected `frame;frame;... count`:\n{}", .lines.len(), format_malformed(.lines))]
+    MalformedLines {
+        path: PathBuf,
+        /// Line number (1-based) and content of each malformed line.
+        lines: Vec<(usize, String)>,
+    },
+    #[error("failed to create output directory {path}: {source}")]
+    CreateOutputDir {
+        path: PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
+    #[error("invalid search color '{color}': {reason}")]
+    InvalidSearchColor { color: String, reason: String },
+    #[error("failed to render flamegraph: {0}")]
+    Render(#[source] std::io::Error),
+    #[error("failed to write {path}: {source}")]
+    WriteOutput {
+        path: PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
+}
+
+/// Lists at most the first ten malformed lines.
+fn format_malformed(lines: &[(usize, String)]) -> String {
+    let mut listed: Vec<String> = lines
+        .iter()
+        .take(10)
+        .map(|(number, line)| format!("  line {}: {}", number, line))
+        .collect();
+    if lines.len() > 10 {
+        listed.push(format!("  ... and {} more", lines.len() - 10));
+    }
+    listed.join("\n")
+}
+
+/// Color scheme of the flamegraph frames.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum FramePalette {
+    /// Inferno's Java multi-color scheme, hashed by frame name.
+    #[default]
+    Java,
+    /// Neutral for frames reached by all ranks, shaded toward red by the share of missing ranks.
+    Coverage,
+    /// One color per frame category decided while merging: Python, CPython, PyTorch, CUDA, NCCL.
+    Category,
+}
+
+/// Flamegraph rendering settings, set by command-line flags or the `[draw]` section of the config file.
+/// Unset values fall back to the config file, then to inferno's defaults.
+#[derive(Debug, Default, Clone, PartialEq, Deserialize, Serialize, clap::Args)]
+#[serde(default, deny_unknown_fields)]
+pub struct DrawOptions {
+    /// Color scheme of the frames
+    #[arg(
+        long = "palette",
+        value_enum,
+        help = "Color scheme of the flamegraph frames [default: java]. `coverage` keeps frames reached by all ranks neutral \
+                and shades frames toward red by the share of missing ranks; `category` colors frames by kind."
+    )]
+    pub palette: Option<FramePalette>,
+
+    /// Job name used in the default title
+    #[arg(long = "job-name", value_name = "NAME", help = "Job name shown in the default title.")]
+    pub job_name: Option<String>,
+
+    #[arg(
+        long = "title",
+        value_name = "TEXT",
+        help = "Title of the flamegraph [default: job name and capture time]."
+    )]
+    pub title: Option<String>,
+
+    #[arg(
+        long = "subtitle",
+        value_name = "TEXT",
+        help = "Subtitle of the flamegraph. Replaces the legend of the coverage and category palettes."
+    )]
+    pub subtitle: Option<String>,
+
+    #[arg(long = "width", value_name = "PIXELS", help = "Image width in pixels [default: fluid].")]
+    pub width: Option<usize>,
+
+    #[arg(long = "frame-height", value_name = "PIXELS", help = "Height of each frame in pixels [default: 16].")]
+    pub frame_height: Option<usize>,
+
+    #[arg(long = "min-width", value_name = "PIXELS", help = "Omit frames narrower than this many pixels [default: 0.01].")]
+    pub min_width: Option<f64>,
+
+    #[arg(long = "font-type", value_name = "FONT", help = "Font of the frame labels [default: monospace].")]
+    pub font_type: Option<String>,
+
+    #[arg(long = "font-size", value_name = "SIZE", help = "Font size of the frame labels [default: 12].")]
+    pub font_size: Option<usize>,
+
+    #[arg(long = "count-name", value_name = "NAME", help = "Name of the counted unit in tooltips [default: samples].")]
+    pub count_name: Option<String>,
+
+    #[arg(long = "search-color", value_name = "#RRGGBB", help = "Color of frames matching a search [default: #e600e6].")]
+    pub search_color: Option<String>,
+
+    #[arg(
+        long = "inverted",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "true",
+        value_name = "BOOL",
+        help = "Draw an icicle graph growing from the top; --inverted=false overrides the config file."
+    )]
+    pub inverted: Option<bool>,
+
+    #[arg(
+        long = "reverse",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "true",
+        value_name = "BOOL",
+        help = "Reverse the stack order, putting leaf frames at the root; --reverse=false overrides the config file."
+    )]
+    pub reverse: Option<bool>,
+}
+
+impl DrawOptions {
+    /// Fills the unset values of `self` from `base`.
+    pub fn or(self, base: DrawOptions) -> DrawOptions {
+        DrawOptions {
+            palette: self.palette.or(base.palette),
+            job_name: self.job_name.or(base.job_name),
+            title: self.title.or(base.title),
+            subtitle: self.subtitle.or(base.subtitle),
+            width: self.width.or(base.width),
+            frame_height: self.frame_height.or(base.frame_height),
+            min_width: self.min_width.or(base.min_width),
+            font_type: self.font_type.or(base.font_type),
+            font_size: self.font_size.or(base.font_size),
+            count_name: self.count_name.or(base.count_name),
+            search_color: self.search_color.or(base.search_color),
+            inverted: self.inverted.or(base.inverted),
+            reverse: self.reverse.or(base.reverse),
+        }
+    }
+
+    /// Copies the settings into inferno's options. The title is resolved by the caller.
+    fn apply(&self, options: &mut Options<'_>) -> Result<(), DrawError> {
+        options.subtitle = self.subtitle.clone();
+        options.image_width = self.width;
+        if let Some(frame_height) = self.frame_height {
+            options.frame_height = frame_height;
+        }
+        if let Some(min_width) = self.min_width {
+            options.min_width = min_width;
+        }
+        if let Some(font_type) = &self.font_type {
+            options.font_type = font_type.clone();
+        }
+        if let Some(font_size) = self.font_size {
+            options.font_size = font_size;
+        }
+        if let Some(count_name) = &self.count_name {
+            options.count_name = count_name.clone();
+        }
+        if let Some(search_color) = &self.search_color {
+            options.search_color = search_color
+                .parse::<SearchColor>()
+                .map_err(|reason| DrawError::InvalidSearchColor { color: search_color.clone(), reason })?;
+        }
+        if self.inverted == Some(true) {
+            options.direction = Direction::Inverted;
+        }
+        options.reverse_stack_order = self.reverse.unwrap_or_default();
+        Ok(())
+    }
+
+    /// Default title: the job name and when the stacks were captured.
+    fn default_title(&self, meta: Option<&FrameMeta>) -> String {
+        let job = self.job_name.as_deref().unwrap_or("Merged call stacks");
+        let mut title = match meta.and_then(|meta| meta.captured_at.as_deref()) {
+            Some(captured_at) => format!("{} - captured {}", job, captured_at),
+            None => job.to_string(),
+        };
+        if meta.is_some_and(|meta| meta.icicle) {
+            title.push_str(" (rooted at leaf frames)");
+        }
+        title
+    }
+}
+
+/// Color of frames reached by every rank.
+const COVERAGE_NEUTRAL: Color = Color { r: 200, g: 200, b: 205 };
+/// Color the gradient ends at, reached only by frames missing every rank.
+const COVERAGE_MISSING: Color = Color { r: 220, g: 30, b: 30 };
+/// Color of frames whose paths are reached by different ranks, whose union would understate the missing ranks.
+const COVERAGE_MIXED: Color = Color { r: 150, g: 110, b: 210 };
+
+/// Blends from the neutral color toward red in proportion to the missing ranks of a frame.
+fn coverage_color(info: &FrameInfo, all_ranks: &BTreeSet<u32>) -> Color {
+    if info.ranks_differ_by_path {
+        return COVERAGE_MIXED;
+    }
+    let missing = all_ranks.difference(&info.ranks).count();
+    coverage_gradient(missing as f64 / all_ranks.len().max(1) as f64)
+}
+
+fn coverage_gradient(missing_share: f64) -> Color {
+    let blend = |from: u8, to: u8| (from as f64 + (to as f64 - from as f64) * missing_share).round() as u8;
+    Color {
+        r: blend(COVERAGE_NEUTRAL.r, COVERAGE_MISSING.r),
+        g: blend(COVERAGE_NEUTRAL.g, COVERAGE_MISSING.g),
+        b: blend(COVERAGE_NEUTRAL.b, COVERAGE_MISSING.b),
+    }
+}
+
+/// Color of the frames of each category.
+fn category_color(category: FrameCategory) -> Color {
+    match category {
+        FrameCategory::PythonUser => Color { r: 80, g: 160, b: 230 },
+        FrameCategory::PythonLibrary => Color { r: 150, g: 195, b: 235 },
+        FrameCategory::Interpreter => Color { r: 190, g: 190, b: 190 },
+        FrameCategory::Torch => Color { r: 240, g: 150, b: 60 },
+        FrameCategory::Cuda => Color { r: 110, g: 190, b: 80 },
+        FrameCategory::Nccl => Color { r: 215, g: 60, b: 60 },
+        FrameCategory::Native => Color { r: 220, g: 200, b: 120 },
+    }
+}
+
+/// Renders a legend as SVG swatches in the subtitle row, which the caller must reserve.
+fn legend_markup(options: &Options<'_>, title: &str, entries: &[(Color, &str)]) -> String {
+    let size = options.font_size;
+    let y = size * 4 - size + 2;
+    // Approximate text advance, matching inferno's default font width factor
+    let text_width = |text: &str| (text.chars().count() as f64 * size as f64 * options.font_width).ceil() as usize;
+    let mut legend = format!(
+        "<g id=\"legend\" font-size=\"{}\" font-family=\"{}\"><text x=\"10\" y=\"{}\">{}</text>",
+        size, options.font_type, y + size - 2, title
+    );
+    let mut x = 10 + text_width(title) + size;
+    for (color, label) in entries {
+        legend.push_str(&format!(
+            "<rect x=\"{}\" y=\"{}\" width=\"{}\" height=\"{}\" fill=\"rgb({},{},{})\" />\
+             <text x=\"{}\" y=\"{}\">{}</text>",
+            x, y, size, size, color.r, color.g, color.b, x + size + 4, y + size - 2, label
+        ));
+        x += size + 4 + text_width(label) + size;
+    }
+    legend.push_str("</g>");
+    legend
+}
+
+/// Inserts extra SVG markup before the closing tag of the document.
+fn inject_before_end(svg: &mut Vec<u8>, markup: &str) {
+    if let Some(pos) = svg.windows(6).rposition(|window| window == b"</svg>") {
+        svg.splice(pos..pos, markup.bytes());
+    }
+}
+
+/// Which per-rank and per-node flamegraphs to draw next to the merged one.
+#[derive(Debug, Default, Clone, clap::Args)]
+pub struct BatchOptions {
+    #[arg(
+        long = "per-rank",
+        help = "Also draw one flamegraph per rank, plus an index.html page linking all flamegraphs."
+    )]
+    pub per_rank: bool,
+
+    #[arg(
+        long = "per-node",
+        help = "Also draw one flamegraph per node, grouping the ranks by the host of their address or the `node` \
+                entry of the URL file, plus an index.html page linking all flamegraphs."
+    )]
+    pub per_node: bool,
+}
+
+impl BatchOptions {
+    pub fn is_enabled(&self) -> bool {
+        self.per_rank || self.per_node
+    }
+}
+
+/// Loads the frame annotations written during merging next to a stack file, if present.
+fn load_meta(input_file_path: &Path) -> Option<FrameMeta> {
+    let meta_path = FrameMeta::sidecar_path(input_file_path);
+    if !meta_path.exists() {
+        return None;
+    }
+    match FrameMeta::load(&meta_path) {
+        Ok(meta) => Some(meta),
+        Err(e) => {
+            eprintln!("Warning: Failed to load frame annotations {}: {}", meta_path.display(), e);
+            None
+        }
+    }
+}
+
+/// Reads the folded stack lines of a merged stack file, skipping blank lines.
+/// Every line must end with a sample count after the stack; all offending lines are reported together.
+fn read_stack_lines(input_file_path: &Path) -> Result<Vec<String>, DrawError> {
+    let stacks = std::fs::read_to_string(input_file_path).map_err(|source| DrawError::ReadInput {
+        path: input_file_path.to_path_buf(),
+        source,
+    })?;
+
+    let mut lines = Vec::new();
+    let mut malformed = Vec::new();
+    for (index, line) in stacks.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let valid = match line.trim_end().rsplit_once(' ') {
+            Some((stack, count)) => !stack.trim().is_empty() && count.parse::<f64>().is_ok(),
+            None => false,
+        };
+        if valid {
+            lines.push(line.to_string());
+        } else {
+            malformed.push((index + 1, line.to_string()));
+        }
+    }
+    if !malformed.is_empty() {
+        return Err(DrawError::MalformedLines {
+            path: input_file_path.to_path_buf(),
+            lines: malformed,
+        });
+    }
+    Ok(lines)
+}
+
+/// Returns the file name of a stack file without its extension, used to name the flamegraphs.
+fn file_stem_of(input_file_path: &Path) -> Result<&str, DrawError> {
+    input_file_path
+        .file_stem()
+        .and_then(std::ffi::OsStr::to_str)
+        .ok_or_else(|| DrawError::InvalidFileName(input_file_path.to_path_buf()))
+}
+
+/// Creates the directory flamegraphs are written to if needed.
+fn create_output_dir(output_dir: &Path) -> Result<(), DrawError> {
+    std::fs::create_dir_all(output_dir).map_err(|source| DrawError::CreateOutputDir {
+        path: output_dir.to_path_buf(),
+        source,
+    })
+}
+
+/// Renders folded stack lines into an SVG flamegraph.
+/// `title_suffix` is appended to the title, e.g. to name the rank a flamegraph is restricted to.
+fn render_svg<'a>(
+    lines: impl IntoIterator<Item = &'a str>,
+    meta: Option<&FrameMeta>,
+    draw_options: &DrawOptions,
+    title_suffix: Option<&str>,
+) -> Result<Vec<u8>, DrawError> {
+    // Fixed per-frame colors for the palettes computed from the annotations
+    let mut palette_map = PaletteMap::default();
+    let mut legend = None;
 
     // Initialize flamegraph generation options with default values
     let mut options = Options::default();
     // Set the color palette for the flamegraph to Java multi-color scheme
     options.colors = Palette::Multi(flamegraph::color::MultiPalette::Java);
+    draw_options.apply(&mut options)?;
+    options.title = draw_options
+        .title
+        .clone()
+        .unwrap_or_else(|| draw_options.default_title(meta));
+    if let Some(suffix) = title_suffix {
+        options.title.push_str(&format!(" - {}", suffix));
+    }
+
+    if let Some(meta) = meta {
+        // Show the present/missing ranks and selected locals as SVG tooltips
+        options.func_frameattrs = meta.to_frameattrs();
+        // Stacks re-rooted at their leaf frames hang down from the top unless --inverted says otherwise
+        if meta.icicle && draw_options.inverted.is_none() {
+            options.direction = Direction::Inverted;
+        }
+    }
+
+    let palette = draw_options.palette.unwrap_or_default();
+    match (palette, meta) {
+        (FramePalette::Java, _) => {}
+        (FramePalette::Coverage, Some(meta)) => {
+            for (name, info) in &meta.frames {
+                palette_map.insert(name, coverage_color(info, &meta.all_ranks));
+            }
+            // An empty subtitle reserves the row the legend is drawn in
+            if options.subtitle.is_none() {
+                options.subtitle = Some(String::new());
+                // 只有一个rank经过的栈帧缺失(n-1)/n的rank
+                let ranks = meta.all_ranks.len().max(1) as f64;
+                let single = (ranks - 1.0) / ranks;
+                let steps = [(0.0, "all ranks"), (0.25, "25% missing"), (0.5, "50% missing"), (0.75, "75% missing")];
+                let mut entries: Vec<_> = steps
+                    .iter()
+                    .filter(|(share, _)| *share < single)
+                    .map(|(share, label)| (coverage_gradient(*share), *label))
+                    .collect();
+                entries.push((coverage_gradient(single), "1 rank only"));
+                entries.push((COVERAGE_MIXED, "ranks differ by path"));
+                legend = Some(legend_markup(&options, "Rank coverage:", &entries));
+            }
+            options.palette_map = Some(&mut palette_map);
+        }
+        (FramePalette::Category, Some(meta)) => {
+            for (name, info) in &meta.frames {
+                palette_map.insert(name, category_color(info.category.unwrap_or_default()));
+            }
+            if options.subtitle.is_none() {
+                options.subtitle = Some(String::new());
+                let entries: Vec<_> = FrameCategory::ALL.iter().map(|c| (category_color(*c), c.label())).collect();
+                legend = Some(legend_markup(&options, "Frame category:", &entries));
+            }
+            options.palette_map = Some(&mut palette_map);
+        }
+        (_, None) => {
+            eprintln!("Warning: No frame annotations found, using the java palette");
+        }
+    }
+
+    // Generate the flamegraph from the input data
+    let mut svg = Vec::new();
+    flamegraph::from_lines(&mut options, lines, &mut svg).map_err(DrawError::Render)?;
+    if let Some(legend) = legend {
+        inject_before_end(&mut svg, &legend);
+    }
+    Ok(svg)
+}
 
+/// Writes a rendered flamegraph into `output_dir` and returns its path.
+fn write_svg(output_dir: &Path, file_name: &str, svg: &[u8]) -> Result<PathBuf, DrawError> {
+    let output_path = output_dir.join(file_name);
+    File::create(&output_path)
+        .and_then(|mut output_file| output_file.write_all(svg))
+        .map_err(|source| DrawError::WriteOutput {
+            path: output_path.clone(),
+            source,
+        })?;
+    println!("Flamegraph generated and saved as {}", output_path.display());
+    Ok(output_path)
+}
+
+/// Generates a flamegraph from a stack trace file and saves it as `<file stem>.svg` in `output_dir`.
+/// Returns the path of the SVG file.
+pub fn draw_frame_graph(file_path: &str, output_dir: &Path, draw_options: &DrawOptions) -> Result<PathBuf, DrawError> {
     // Convert the input file path string to a PathBuf
     let input_file_path = PathBuf::from(file_path);
     // Extract the file name without the extension from the input file path
-    let file_stem = input_file_path.file_stem()
-        .and_then(std::ffi::OsStr::to_str)
-        .expect("Failed to get file stem");
-
-    // Determine the output directory
-    let output_dir = match output_path {
-        // Use the specified output path if provided
-        Some(path) => PathBuf::from(path),
-        // Use the default output path in /tmp/output_xxxx/flame_svg
-        None => {
-            use chrono::Local;
-            let date = Local::now().format("%Y%m%d").to_string();
-            let default_output_dir = PathBuf::from("/tmp").join(format!("output_{}", date)).join("flame_svg");
-            default_output_dir
-        }
-    };
+    let file_stem = file_stem_of(&input_file_path)?;
+
+    // Read the input file containing stack trace data
+    let lines = read_stack_lines(&input_file_path)?;
+    let meta = load_meta(&input_file_path);
+
+    create_output_dir(output_dir)?;
+    let svg = render_svg(lines.iter().map(String::as_str), meta.as_ref(), draw_options, None)?;
+    write_svg(output_dir, &format!("{}.svg", file_stem), &svg)
+}
+
+/// Draws the merged flamegraph of a stack trace file plus one flamegraph per rank and/or per node,
+/// and writes an `<stem>.index.html` page linking them. Returns the path of the index page.
+/// Splitting needs the per-stack ranks recorded in the frame annotations during merging.
+pub fn draw_frame_graph_batch(
+    file_path: &str,
+    output_dir: &Path,
+    draw_options: &DrawOptions,
+    batch: &BatchOptions,
+) -> Result<PathBuf, DrawError> {
+    let input_file_path = PathBuf::from(file_path);
+    let file_stem = file_stem_of(&input_file_path)?;
+    let stacks = read_stack_lines(&input_file_path)?;
+    let lines: Vec<&str> = stacks.iter().map(String::as_str).collect();
+    let meta = load_meta(&input_file_path);
+    create_output_dir(output_dir)?;
+
+    let merged = render_svg(lines.iter().copied(), meta.as_ref(), draw_options, None)?;
+    let merged_name = format!("{}.svg", file_stem);
+    write_svg(output_dir, &merged_name, &merged)?;
+
+    let mut node_links = Vec::new();
+    let mut rank_links = Vec::new();
+    match &meta {
+        Some(meta) if meta.stack_ranks.len() == lines.len() => {
+            // Keeps the merged stacks that at least one of `ranks` ends with
+            let select = |ranks: &BTreeSet<u32>| {
+                lines
+                    .iter()
+                    .zip(&meta.stack_ranks)
+                    .filter(|(_, ending)| !ending.is_disjoint(ranks))
+                    .map(|(line, _)| *line)
+                    .collect::<Vec<&str>>()
+            };
 
-    // Create the output directory if it doesn't exist
-    if let Err(e) = std::fs::create_dir_all(&output_dir) {
-        panic!("Failed to create output directory: {}", e);
+            if batch.per_node {
+                if meta.rank_nodes.is_empty() {
+                    eprintln!("Warning: No node of any rank was recorded while merging, skipping per-node flamegraphs");
+                }
+                for (node, ranks) in meta.nodes() {
+                    let rank_list: Vec<u32> = ranks.iter().cloned().collect();
+                    let label = format!("node {} (ranks {})", node, format_rank_ranges(&rank_list));
+                    let svg = render_svg(select(&ranks), Some(meta), draw_options, Some(&label))?;
+                    let name = format!("{}.node-{}.svg", file_stem, file_name_safe(node));
+                    write_svg(output_dir, &name, &svg)?;
+                    node_links.push((label, name));
+                }
+            }
+            if batch.per_rank {
+                for rank in &meta.all_ranks {
+                    let ranks = BTreeSet::from([*rank]);
+                    let selected = select(&ranks);
+                    if selected.is_empty() {
+                        eprintln!("Warning: No stack of rank {} in {}, skipping its flamegraph", rank, file_path);
+                        continue;
+                    }
+                    let label = format!("rank {}", rank);
+                    let svg = render_svg(selected, Some(meta), draw_options, Some(&label))?;
+                    let name = format!("{}.rank{}.svg", file_stem, rank);
+                    write_svg(output_dir, &name, &svg)?;
+                    rank_links.push((label, name));
+                }
+            }
+        }
+        _ => {
+            eprintln!(
+                "Warning: No per-stack ranks found in the annotations next to {}, drawing the merged flamegraph only. \
+                 Merge the stacks again with this version to split them by rank or node.",
+                file_path
+            );
+        }
     }
 
-    // Construct the output file path
-    let mut output_path = output_dir.clone();
-    output_path.push(format!("{}.svg", file_stem));
+    let index_path = output_dir.join(format!("{}.index.html", file_stem));
+    let index = index_html(file_stem, &merged_name, &node_links, &rank_links);
+    std::fs::write(&index_path, index).map_err(|source| DrawError::WriteOutput {
+        path: index_path.clone(),
+        source,
+    })?;
+    println!("Flamegraph index saved as {}", index_path.display());
+    Ok(index_path)
+}
 
-    // Create the output file for the generated flamegraph
-    let mut output_file = File::create(output_path.clone()).expect("Failed to create SVG file");
-    // Generate the flamegraph from the input data and write it to the output file
-    flamegraph::from_reader(&mut options, reader, &mut output_file).expect("Failed to generate flamegraph");
+/// Replaces characters that are awkward in file names, e.g. the `:` of IPv6 hosts.
+fn file_name_safe(name: &str) -> String {
+    name.chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '.' || c == '-' || c == '_' { c } else { '_' })
+        .collect()
+}
 
-    // Print a message indicating that the flamegraph has been generated and saved
-    println!("Flamegraph generated and saved as {}", output_path.display());
+fn html_escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+/// Builds a static HTML page linking the merged, per-node and per-rank flamegraphs.
+fn index_html(stem: &str, merged: &str, nodes: &[(String, String)], ranks: &[(String, String)]) -> String {
+    let list = |links: &[(String, String)]| {
+        links
+            .iter()
+            .map(|(label, href)| format!("<li><a href=\"{}\">{}</a></li>\n", html_escape(href), html_escape(label)))
+            .collect::<String>()
+    };
+    let mut html = format!(
+        "<!DOCTYPE html>\n<html>\n<head><meta charset=\"utf-8\"><title>{0}</title></head>\n<body>\n<h1>{0}</h1>\n\
+         <h2>Merged</h2>\n<ul>\n<li><a href=\"{1}\">all ranks</a></li>\n</ul>\n",
+        html_escape(stem),
+        html_escape(merged)
+    );
+    if !nodes.is_empty() {
+        html.push_str(&format!("<h2>Per node</h2>\n<ul>\n{}</ul>\n", list(nodes)));
+    }
+    if !ranks.is_empty() {
+        html.push_str(&format!("<h2>Per rank</h2>\n<ul>\n{}</ul>\n", list(ranks)));
+    }
+    html.push_str("</body>\n</html>\n");
+    html
 }
 
 #[cfg(test)]
-use std::fs;
 mod tests {
     use super::*;
-    use std::env;
 
     /// Tests the `draw_frame_graph` function.
     /// Checks if an SVG file with the same name as the input file exists in the test directory.
@@ -74,11 +608,11 @@ mod tests {
         let input_file_path = project_root.join("test").join("merged_output.txt");
         let input_file_path_str = input_file_path.to_str().expect("Failed to convert input path to string");
         // Build the output directory path
-        let output_dir = project_root.join("test");
-        let output_dir_str = output_dir.to_str().expect("Failed to convert output path to string");
+        let output_dir = std::env::temp_dir().join(format!("flame_test_draw_{}", std::process::id()));
+        let output_dir_str = output_dir.to_string_lossy();
 
         // Call the draw_frame_graph function
-        draw_frame_graph(input_file_path_str, Some(output_dir_str));
+        draw_frame_graph(input_file_path_str, &output_dir, &DrawOptions::default()).expect("Drawing failed");
 
         // Get the expected SVG file name
         let expected_file_name = input_file_path.file_stem()
@@ -99,7 +633,127 @@ mod tests {
             });
             assert!(found, "SVG file '{}' should exist in {}", expected_svg_name, output_dir_str);
         } else {
-            assert!(false, "Failed to read output directory: {}", output_dir_str);
+            panic!("Failed to read output directory: {}", output_dir_str);
+        }
+    }
+
+    /// Frames reached by all ranks stay neutral; frames missing ranks shade toward red.
+    #[test]
+    fn test_coverage_color() {
+        let all_ranks = BTreeSet::from([0, 1, 2, 3]);
+        let full = FrameInfo { ranks: all_ranks.clone(), ..Default::default() };
+        let single = FrameInfo { ranks: BTreeSet::from([3]), ..Default::default() };
+
+        assert_eq!(coverage_color(&full, &all_ranks), COVERAGE_NEUTRAL);
+        let straggler = coverage_color(&single, &all_ranks);
+        assert!(straggler.r > straggler.g && straggler.g < COVERAGE_NEUTRAL.g);
+        assert_eq!(straggler, coverage_gradient(0.75));
+
+        let mixed = FrameInfo { ranks: all_ranks.clone(), ranks_differ_by_path: true, ..Default::default() };
+        assert_eq!(coverage_color(&mixed, &all_ranks), COVERAGE_MIXED);
+    }
+
+    /// Every frame category gets its own color.
+    #[test]
+    fn test_category_colors_are_distinct() {
+        let colors: BTreeSet<_> = FrameCategory::ALL
+            .iter()
+            .map(|category| {
+                let color = category_color(*category);
+                (color.r, color.g, color.b)
+            })
+            .collect();
+        assert_eq!(colors.len(), FrameCategory::ALL.len());
+    }
+
+    /// Batch drawing writes the merged, per-node and per-rank flamegraphs and an index linking them.
+    #[test]
+    fn test_draw_frame_graph_batch() {
+        let output_dir = std::env::temp_dir().join(format!("flame_test_batch_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&output_dir);
+        let json_data = std::fs::read_to_string("test/pyframe_locals.json").expect("Failed to read input file");
+        let options = crate::process::MergeOptions {
+            rank_nodes: [(0, "10.0.0.5"), (1, "10.0.0.5"), (2, "10.0.0.6"), (3, "10.0.0.6")]
+                .into_iter()
+                .map(|(rank, node)| (rank, node.to_string()))
+                .collect(),
+            ..Default::default()
+        };
+        let stack_file = crate::process::process_and_merge_callstacks(&json_data, vec![0, 1, 2, 3], &output_dir, &options)
+            .expect("Processing failed");
+
+        let batch = BatchOptions { per_rank: true, per_node: true };
+        let index_path = draw_frame_graph_batch(
+            stack_file.to_str().expect("Invalid stack file path"),
+            &output_dir,
+            &DrawOptions::default(),
+            &batch,
+        )
+        .expect("Batch drawing failed");
+
+        let stem = stack_file.file_stem().and_then(std::ffi::OsStr::to_str).expect("Failed to get file stem");
+        let index = std::fs::read_to_string(&index_path).expect("Failed to read index page");
+        let expected = [
+            format!("{}.svg", stem),
+            format!("{}.node-10.0.0.5.svg", stem),
+            format!("{}.node-10.0.0.6.svg", stem),
+            format!("{}.rank0.svg", stem),
+            format!("{}.rank3.svg", stem),
+        ];
+        for name in &expected {
+            assert!(output_dir.join(name).exists(), "{} should be drawn", name);
+            assert!(index.contains(&format!("href=\"{}\"", name)), "index should link {}", name);
+        }
+        let rank3 = std::fs::read_to_string(output_dir.join(&expected[4])).expect("Failed to read rank flamegraph");
+        assert!(rank3.contains(" - rank 3"));
+    }
+
+    /// Malformed lines are reported together with their line numbers instead of panicking.
+    #[test]
+    fn test_malformed_lines_are_reported() {
+        let dir = std::env::temp_dir().join(format!("flame_test_malformed_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).expect("Failed to create test directory");
+        let input = dir.join("broken.txt");
+        std::fs::write(&input, "main;train 1\nmain;eval\n\nmain;train x\n").expect("Failed to write test input");
+
+        let err = draw_frame_graph(input.to_str().expect("Invalid path"), &dir, &DrawOptions::default())
+            .expect_err("Malformed input should fail");
+        match &err {
+            DrawError::MalformedLines { lines, .. } => {
+                assert_eq!(lines.iter().map(|(number, _)| *number).collect::<Vec<_>>(), vec![2, 4]);
+            }
+            other => panic!("unexpected error {}", other),
         }
+        assert!(err.to_string().contains("line 2: main;eval"));
+
+        let missing = draw_frame_graph("/nonexistent/stacks.txt", &dir, &DrawOptions::default());
+        assert!(matches!(missing, Err(DrawError::ReadInput { .. })));
     }
-}
\ No newline at end of file
+
+    /// Command-line values win over the config file, which fills the remaining settings.
+    #[test]
+    fn test_draw_options_layering() {
+        let config: DrawOptions =
+            toml::from_str("title = \"from config\"\nframe_height = 20\npalette = \"coverage\"\nreverse = true\ninverted = true")
+                .expect("Invalid drawing config");
+        let cli = DrawOptions {
+            title: Some("from cli".to_string()),
+            reverse: Some(false),
+            ..Default::default()
+        };
+
+        let merged = cli.or(config);
+        assert_eq!(merged.title.as_deref(), Some("from cli"));
+        assert_eq!(merged.frame_height, Some(20));
+        assert_eq!(merged.palette, Some(FramePalette::Coverage));
+        assert_eq!((merged.inverted, merged.reverse), (Some(true), Some(false)));
+
+        let mut options = Options::default();
+        merged.apply(&mut options).expect("Valid drawing options");
+        assert_eq!(options.frame_height, 20);
+        assert!(!options.reverse_stack_order);
+
+        let invalid = DrawOptions { search_color: Some("magenta".to_string()), ..Default::default() };
+        assert!(matches!(invalid.apply(&mut options), Err(DrawError::InvalidSearchColor { .. })));
+    }
+}
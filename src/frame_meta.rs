@@ -0,0 +1,292 @@
+use inferno::flamegraph::FuncFrameAttrsMap;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufReader, Write};
+use std::path::{Path, PathBuf};
+
+use crate::process::format_rank_ranges;
+use crate::process_info::ProcessInfo;
+
+/// Semantic category of a frame, decided while merging.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FrameCategory {
+    /// Python code of the training job itself.
+    PythonUser,
+    /// Python code of installed packages and the standard library.
+    PythonLibrary,
+    /// The CPython interpreter.
+    Interpreter,
+    /// PyTorch C++ code and its Python bindings.
+    Torch,
+    /// CUDA runtime and driver calls (`cuda*`).
+    Cuda,
+    /// NCCL and the c10d process groups.
+    Nccl,
+    /// Any other native code.
+    #[default]
+    Native,
+}
+
+impl FrameCategory {
+    pub const ALL: [FrameCategory; 7] = [
+        FrameCategory::PythonUser,
+        FrameCategory::PythonLibrary,
+        FrameCategory::Interpreter,
+        FrameCategory::Torch,
+        FrameCategory::Cuda,
+        FrameCategory::Nccl,
+        FrameCategory::Native,
+    ];
+
+    pub fn label(self) -> &'static str {
+        match self {
+            FrameCategory::PythonUser => "Python (user)",
+            FrameCategory::PythonLibrary => "Python (library)",
+            FrameCategory::Interpreter => "CPython",
+            FrameCategory::Torch => "PyTorch C++",
+            FrameCategory::Cuda => "CUDA",
+            FrameCategory::Nccl => "NCCL/c10d",
+            FrameCategory::Native => "Native",
+        }
+    }
+}
+
+/// Annotations attached to one frame name of a merged stack file.
+#[derive(Debug, Default, Clone, Deserialize, Serialize)]
+pub struct FrameInfo {
+    /// Ranks whose stack passes through this frame.
+    #[serde(default)]
+    pub ranks: BTreeSet<u32>,
+    /// Category of the frame, absent in annotations written by older versions.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub category: Option<FrameCategory>,
+    /// Selected Python locals: variable name -> value -> ranks reporting that value.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub locals: BTreeMap<String, BTreeMap<String, BTreeSet<u32>>>,
+    /// `(rank, worker)` of the worker processes, e.g. DataLoader workers, passing through this frame.
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub workers: BTreeSet<(u32, u32)>,
+    /// The frame is reached through several paths by different sets of ranks, so `ranks` is their
+    /// union and does not tell which ranks miss the frame on a given path.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub ranks_differ_by_path: bool,
+}
+
+impl FrameInfo {
+    /// Adds the local variable values of a trie node to this frame.
+    pub fn merge_locals(&mut self, locals: &BTreeMap<String, BTreeMap<String, BTreeSet<u32>>>) {
+        for (name, values) in locals {
+            let entry = self.locals.entry(name.clone()).or_default();
+            for (value, ranks) in values {
+                entry.entry(value.clone()).or_default().extend(ranks);
+            }
+        }
+    }
+
+    /// Describes the ranks reaching this frame and the ranks missing from it,
+    /// e.g. `ranks 0-6 | missing 7`, or `ranks differ by path, 0-7 over all paths`.
+    pub fn ranks_summary(&self, all_ranks: &BTreeSet<u32>) -> String {
+        let present: Vec<u32> = self.ranks.iter().cloned().collect();
+        if self.ranks_differ_by_path {
+            return format!("ranks differ by path, {} over all paths", format_rank_ranges(&present));
+        }
+        let missing: Vec<u32> = all_ranks.difference(&self.ranks).cloned().collect();
+        let mut summary = format!("ranks {}", format_rank_ranges(&present));
+        if !missing.is_empty() {
+            summary.push_str(&format!(" | missing {}", format_rank_ranges(&missing)));
+        }
+        summary
+    }
+
+    /// Names the worker processes reaching this frame, grouped by worker index,
+    /// e.g. `rank 3 / worker 1, ranks 0-7 / worker 0`.
+    pub fn workers_summary(&self) -> String {
+        let mut by_worker: BTreeMap<u32, Vec<u32>> = BTreeMap::new();
+        for (rank, worker) in &self.workers {
+            by_worker.entry(*worker).or_default().push(*rank);
+        }
+        by_worker
+            .into_iter()
+            .map(|(worker, ranks)| {
+                let label = if ranks.len() == 1 { "rank" } else { "ranks" };
+                format!("{} {} / worker {}", label, format_rank_ranges(&ranks), worker)
+            })
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+
+    /// Summarizes the local values per rank, e.g. `step=1200 on ranks 0-6, step=1199 on rank 7`.
+    /// The most common value of each variable is listed first.
+    pub fn locals_summary(&self) -> String {
+        let mut parts = Vec::new();
+        for (name, values) in &self.locals {
+            let mut values: Vec<_> = values.iter().collect();
+            values.sort_by(|a, b| b.1.len().cmp(&a.1.len()).then_with(|| b.0.cmp(a.0)));
+            for (value, ranks) in values {
+                let ranks: Vec<u32> = ranks.iter().cloned().collect();
+                let label = if ranks.len() == 1 { "rank" } else { "ranks" };
+                parts.push(format!("{}={} on {} {}", name, value, label, format_rank_ranges(&ranks)));
+            }
+        }
+        parts.join(", ")
+    }
+}
+
+/// Replaces the separators of inferno's frame attribute lines in `text` by spaces.
+fn attr_text(text: &str) -> String {
+    text.replace(['\t', '\r', '\n'], " ")
+}
+
+/// Frame annotations written next to a merged stack file and picked up when drawing.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct FrameMeta {
+    /// Local time the stacks were merged, used in the default flamegraph title.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub captured_at: Option<String>,
+    /// The stacks are re-rooted at their leaf frames and should be drawn as an icicle graph.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub icicle: bool,
+    pub all_ranks: BTreeSet<u32>,
+    /// Node (host) each rank ran on, used to draw one flamegraph per node.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub rank_nodes: BTreeMap<u32, String>,
+    /// Ranks whose stack ends with each line of the merged stack file, in file order.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub stack_ranks: Vec<BTreeSet<u32>>,
+    /// Host, PID and command line of the process behind each rank.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub processes: BTreeMap<u32, ProcessInfo>,
+    pub frames: BTreeMap<String, FrameInfo>,
+}
+
+impl FrameMeta {
+    /// Returns the path of the annotation file belonging to a merged stack file.
+    pub fn sidecar_path(stack_file: &Path) -> PathBuf {
+        stack_file.with_extension("meta.json")
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), Box<dyn Error>> {
+        let file = File::create(path)?;
+        serde_json::to_writer_pretty(file, self)?;
+        Ok(())
+    }
+
+    pub fn load(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let file = File::open(path)?;
+        Ok(serde_json::from_reader(BufReader::new(file))?)
+    }
+
+    /// Groups the ranks by the node they ran on.
+    pub fn nodes(&self) -> BTreeMap<&str, BTreeSet<u32>> {
+        let mut nodes: BTreeMap<&str, BTreeSet<u32>> = BTreeMap::new();
+        for (rank, node) in &self.rank_nodes {
+            nodes.entry(node.as_str()).or_default().insert(*rank);
+        }
+        nodes
+    }
+
+    /// Names the processes of the few ranks setting a frame apart, i.e. the smaller of its present
+    /// and missing ranks when at most `MAX_LISTED_PROCESSES`. E.g. `rank 7 on gpu002 pid 4242`.
+    fn processes_summary(&self, info: &FrameInfo) -> String {
+        const MAX_LISTED_PROCESSES: usize = 4;
+        if info.ranks_differ_by_path {
+            return String::new();
+        }
+        let missing: Vec<u32> = self.all_ranks.difference(&info.ranks).cloned().collect();
+        let present: Vec<u32> = info.ranks.iter().cloned().collect();
+        let listed = match missing.len() {
+            0 => return String::new(),
+            count if count <= present.len() => missing,
+            _ => present,
+        };
+        if listed.len() > MAX_LISTED_PROCESSES {
+            return String::new();
+        }
+        listed
+            .iter()
+            .filter_map(|rank| Some(format!("rank {} on {}", rank, self.processes.get(rank)?.summary())))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+
+    /// Builds inferno frame attributes so the SVG tooltips show the present and missing ranks,
+    /// the selected locals, the worker processes and the processes of the ranks setting each frame apart.
+    /// Tabs and line breaks, which would split the attribute lines, are replaced by spaces.
+    pub fn to_frameattrs(&self) -> FuncFrameAttrsMap {
+        let mut attrs = String::new();
+        for (name, info) in &self.frames {
+            let mut title = format!("{} | {}", name, info.ranks_summary(&self.all_ranks));
+            let locals = info.locals_summary();
+            if !locals.is_empty() {
+                title.push_str(&format!(" | {}", locals));
+            }
+            let workers = info.workers_summary();
+            if !workers.is_empty() {
+                title.push_str(&format!(" | {}", workers));
+            }
+            let processes = self.processes_summary(info);
+            if !processes.is_empty() {
+                title.push_str(&format!(" | {}", processes));
+            }
+            attrs.push_str(&format!("{}\ttitle={}\n", attr_text(name), attr_text(&title)));
+        }
+        FuncFrameAttrsMap::from_reader(attrs.as_bytes()).expect("reading from memory cannot fail")
+    }
+
+    /// Writes a plain-text table of the process behind each rank.
+    pub fn write_processes_report<W: Write>(&self, writer: &mut W) -> std::io::Result<()> {
+        writeln!(writer, "# Processes")?;
+        for (rank, info) in &self.processes {
+            let field = |value: Option<String>| value.unwrap_or_else(|| "-".to_string());
+            writeln!(
+                writer,
+                "rank {:<5} {:<20} pid {:<8} python {:<8} probing {:<8} {}",
+                rank,
+                field(info.hostname.clone()),
+                field(info.pid.map(|pid| pid.to_string())),
+                field(info.python_version.clone()),
+                field(info.probing_version.clone()),
+                field(info.cmdline.clone()),
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Writes a plain-text report of the frames carrying selected locals.
+    pub fn write_locals_report<W: Write>(&self, writer: &mut W) -> std::io::Result<()> {
+        writeln!(writer, "# Selected locals per frame")?;
+        for (name, info) in &self.frames {
+            let summary = info.locals_summary();
+            if summary.is_empty() {
+                continue;
+            }
+            writeln!(writer, "{}", name)?;
+            writeln!(writer, "    {}", summary)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Tabs and line breaks in frame names and locals stay inside one attribute line.
+    #[test]
+    fn test_frameattrs_escape_separators() {
+        let mut info = FrameInfo { ranks: BTreeSet::from([0]), ..Default::default() };
+        info.locals.insert("batch".to_string(), BTreeMap::from([("a\nb".to_string(), BTreeSet::from([0]))]));
+        let meta = FrameMeta {
+            all_ranks: BTreeSet::from([0]),
+            frames: BTreeMap::from([("step\t(train.py:1)".to_string(), info)]),
+            ..Default::default()
+        };
+
+        let expected = "step (train.py:1)\ttitle=step (train.py:1) | ranks 0 | batch=a b on rank 0\n";
+        let expected = FuncFrameAttrsMap::from_reader(expected.as_bytes()).expect("reading from memory cannot fail");
+        assert_eq!(meta.to_frameattrs(), expected);
+    }
+}
@@ -0,0 +1,389 @@
+use regex::Regex;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::LazyLock;
+
+use crate::process::{CFrame, Frame, PyFrame, RankStacks, ThreadStack};
+
+/// Tool that produced a stack dump.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum DumpFormat {
+    /// Guess the format from the content.
+    #[default]
+    Auto,
+    /// `py-spy dump --json`.
+    PySpy,
+    /// GDB `thread apply all bt`.
+    Gdb,
+    /// `perf script`; the last sample of each thread is taken as its stack.
+    Perf,
+}
+
+impl DumpFormat {
+    /// Guesses the format of a dump: JSON is py-spy, `Thread N (...)` headers with `#N` frames are GDB,
+    /// anything else is read as perf script.
+    fn detect(content: &str) -> DumpFormat {
+        let trimmed = content.trim_start();
+        if trimmed.starts_with('[') || trimmed.starts_with('{') {
+            DumpFormat::PySpy
+        } else if content.lines().any(|line| line.starts_with("Thread ")) && content.lines().any(|line| line.starts_with('#')) {
+            DumpFormat::Gdb
+        } else {
+            DumpFormat::Perf
+        }
+    }
+}
+
+/// Converts a stack dump of one process into its call stacks, one per thread.
+pub(crate) fn import_dump(content: &str, format: DumpFormat) -> Result<RankStacks, String> {
+    let format = match format {
+        DumpFormat::Auto => DumpFormat::detect(content),
+        format => format,
+    };
+    let threads = match format {
+        DumpFormat::PySpy => parse_pyspy(content)?,
+        DumpFormat::Gdb => parse_gdb(content),
+        DumpFormat::Perf | DumpFormat::Auto => parse_perf(content),
+    };
+    if threads.is_empty() {
+        return Err(format!("No call stack found in the {:?} dump", format));
+    }
+    Ok(RankStacks::ThreadList(threads))
+}
+
+/// `rank3`, `rank_3`, `rank-3` or `rank.3` in a dump file name.
+static RANK_IN_NAME: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"rank[_\-.]?(\d+)").expect("valid regex"));
+
+/// Returns the rank in a dump file name, e.g. 3 for `rank3.json`, `pyspy_rank-3.txt` or `3.txt`:
+/// the number after `rank`, else the whole file stem when it is a number. Other numbers, such as
+/// dates or PIDs, are not taken for a rank.
+pub(crate) fn rank_from_file_name(path: &Path) -> Result<u32, String> {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy().to_ascii_lowercase();
+    let rank = match RANK_IN_NAME.captures(&stem) {
+        Some(captures) => captures[1].parse().ok(),
+        None => stem.parse().ok(),
+    };
+    rank.ok_or_else(|| format!("No rank in the file name {}, name it rank<N> or <N> or pass --rank-list", path.display()))
+}
+
+/// Thread of `py-spy dump --json`.
+#[derive(Deserialize)]
+struct PySpyThread {
+    #[serde(default)]
+    thread_id: Option<u64>,
+    #[serde(default)]
+    os_thread_id: Option<u64>,
+    #[serde(default)]
+    thread_name: Option<String>,
+    #[serde(default)]
+    frames: Vec<PySpyFrame>,
+}
+
+#[derive(Deserialize)]
+struct PySpyFrame {
+    name: String,
+    filename: String,
+    #[serde(default)]
+    line: i64,
+    /// Present with `py-spy dump --locals`.
+    #[serde(default)]
+    locals: Option<Vec<PySpyLocal>>,
+}
+
+#[derive(Deserialize)]
+struct PySpyLocal {
+    name: String,
+    #[serde(default)]
+    repr: Option<String>,
+}
+
+/// Reads `py-spy dump --json`, whose frames are innermost first like Probing's.
+fn parse_pyspy(content: &str) -> Result<Vec<ThreadStack>, String> {
+    let threads: Vec<PySpyThread> = serde_json::from_str(content).map_err(|e| format!("Invalid py-spy JSON: {}", e))?;
+    Ok(threads
+        .into_iter()
+        .map(|thread| {
+            let frames = thread
+                .frames
+                .into_iter()
+                .map(|frame| {
+                    let locals: serde_json::Map<String, serde_json::Value> = frame
+                        .locals
+                        .unwrap_or_default()
+                        .into_iter()
+                        .filter_map(|local| Some((local.name, serde_json::Value::String(local.repr?))))
+                        .collect();
+                    Frame::PyFrame(PyFrame {
+                        file: frame.filename,
+                        func: frame.name,
+                        lineno: u32::try_from(frame.line).unwrap_or_default(),
+                        locals: serde_json::Value::Object(locals),
+                    })
+                })
+                .collect();
+            ThreadStack {
+                id: thread.os_thread_id.or(thread.thread_id),
+                main: thread.thread_name.as_deref() == Some("MainThread"),
+                name: thread.thread_name,
+                frames,
+                tasks: Vec::new(),
+            }
+        })
+        .collect())
+}
+
+/// Source file and line, or shared library, at the end of a GDB frame.
+static GDB_LOCATION: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"\s+(?:at (\S+):(\d+)|from (\S+))$").expect("valid regex"));
+
+/// Splits the text of a GDB frame after `#N`, e.g. `0x7f3c in foo (x=1) at foo.c:12`, into a frame.
+fn parse_gdb_frame(text: &str) -> CFrame {
+    let (ip, mut rest) = match text.split_once(" in ") {
+        Some((ip, rest)) if ip.starts_with("0x") => (ip.to_string(), rest),
+        _ => (String::new(), text),
+    };
+    let mut file = String::new();
+    let mut lineno = 0;
+    if let Some(captures) = GDB_LOCATION.captures(rest) {
+        match (captures.get(1), captures.get(2), captures.get(3)) {
+            (Some(path), Some(line), _) => {
+                file = path.as_str().to_string();
+                lineno = line.as_str().parse().unwrap_or_default();
+            }
+            (_, _, Some(library)) => file = library.as_str().to_string(),
+            _ => {}
+        }
+        rest = &rest[..captures.get(0).map_or(rest.len(), |m| m.start())];
+    }
+    // 去掉末尾的参数列表，函数名本身可能带括号，如 std::function<void ()>::operator()
+    let rest = rest.trim();
+    let mut func = rest;
+    if rest.ends_with(')') {
+        let mut depth = 0;
+        for (index, c) in rest.char_indices().rev() {
+            match c {
+                ')' => depth += 1,
+                '(' => {
+                    depth -= 1;
+                    if depth == 0 {
+                        func = rest[..index].trim_end();
+                        break;
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+    CFrame { file, func: func.to_string(), ip, lineno }
+}
+
+/// `Thread 2 (Thread 0x7f.. (LWP 4242) "pt_data_worker"):` headers of GDB backtraces.
+static GDB_THREAD: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"^Thread (\d+) \((.*)\):\s*$").expect("valid regex"));
+static GDB_LWP: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"LWP (\d+)").expect("valid regex"));
+static GDB_THREAD_NAME: LazyLock<Regex> = LazyLock::new(|| Regex::new(r#""([^"]*)"\)?$"#).expect("valid regex"));
+
+/// Reads GDB `thread apply all bt` output. Thread 1 is the main thread.
+fn parse_gdb(content: &str) -> Vec<ThreadStack> {
+    // 过长的栈帧会被GDB折行，缩进的行接在上一行之后
+    let mut lines: Vec<String> = Vec::new();
+    for line in content.lines() {
+        match lines.last_mut() {
+            Some(last) if last.starts_with('#') && line.starts_with(char::is_whitespace) && !line.trim().is_empty() => {
+                last.push(' ');
+                last.push_str(line.trim());
+            }
+            _ => lines.push(line.to_string()),
+        }
+    }
+
+    let mut threads = Vec::new();
+    for line in &lines {
+        if let Some(captures) = GDB_THREAD.captures(line) {
+            let details = &captures[2];
+            threads.push(ThreadStack {
+                id: GDB_LWP.captures(details).and_then(|lwp| lwp[1].parse().ok()),
+                name: GDB_THREAD_NAME.captures(details).map(|name| name[1].to_string()),
+                main: &captures[1] == "1",
+                frames: Vec::new(),
+                tasks: Vec::new(),
+            });
+        } else if let Some(frame) = line.strip_prefix('#') {
+            let text = frame.trim_start_matches(|c: char| c.is_ascii_digit()).trim();
+            // 单线程程序的bt没有Thread行
+            if threads.is_empty() {
+                threads.push(ThreadStack { id: None, name: None, main: true, frames: Vec::new(), tasks: Vec::new() });
+            }
+            if let Some(thread) = threads.last_mut() {
+                thread.frames.push(Frame::CFrame(parse_gdb_frame(text)));
+            }
+        }
+    }
+    threads
+}
+
+/// Turns a `perf script` frame symbol into a frame; `py::func:file` symbols of Python's perf
+/// trampolines (`python -X perf`) become Python frames.
+fn perf_frame(address: &str, symbol: &str, dso: &str) -> Frame {
+    // 去掉符号的偏移量，如 func+0x1a
+    let symbol = match symbol.rsplit_once("+0x") {
+        Some((name, offset)) if offset.chars().all(|c| c.is_ascii_hexdigit()) => name,
+        _ => symbol,
+    };
+    if let Some((func, file)) = symbol.strip_prefix("py::").and_then(|python| python.split_once(':')) {
+        return Frame::PyFrame(PyFrame {
+            file: file.to_string(),
+            func: func.to_string(),
+            lineno: 0,
+            locals: serde_json::Value::Null,
+        });
+    }
+    Frame::CFrame(CFrame { file: dso.to_string(), func: symbol.to_string(), ip: format!("0x{}", address), lineno: 0 })
+}
+
+/// Sample header of `perf script`: command, optional PID, TID, optional CPU and timestamp.
+static PERF_SAMPLE: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"^(\S.*?)\s+(?:(\d+)/)?(\d+)\s+(?:\[\d+\]\s+)?\d+\.\d+:").expect("valid regex")
+});
+/// Frame line of `perf script`: address, symbol and DSO.
+static PERF_FRAME: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^\s+([0-9a-fA-F]+)\s+(.+?)\s+\((.*)\)\s*$").expect("valid regex"));
+
+/// Reads `perf script` output. Its samples span the recording, so the last sample of each thread
+/// is taken as that thread's stack; the thread whose TID equals the PID is the main thread.
+fn parse_perf(content: &str) -> Vec<ThreadStack> {
+    let mut threads: BTreeMap<u64, ThreadStack> = BTreeMap::new();
+    let mut current: Option<u64> = None;
+    for line in content.lines() {
+        if let Some(captures) = PERF_SAMPLE.captures(line) {
+            let tid: u64 = captures[3].parse().unwrap_or_default();
+            let pid: Option<u64> = captures.get(2).and_then(|pid| pid.as_str().parse().ok());
+            threads.insert(
+                tid,
+                ThreadStack {
+                    id: Some(tid),
+                    name: Some(captures[1].trim().to_string()),
+                    main: pid == Some(tid),
+                    frames: Vec::new(),
+                    tasks: Vec::new(),
+                },
+            );
+            current = Some(tid);
+        } else if let Some(captures) = PERF_FRAME.captures(line) {
+            if let Some(thread) = current.and_then(|tid| threads.get_mut(&tid)) {
+                thread.frames.push(perf_frame(&captures[1], &captures[2], &captures[3]));
+            }
+        } else if line.trim().is_empty() {
+            current = None;
+        }
+    }
+    threads.into_values().filter(|thread| !thread.frames.is_empty()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Names of the frames of each thread, innermost first.
+    fn frame_names(stacks: RankStacks) -> Vec<(Option<String>, bool, Vec<String>)> {
+        stacks
+            .into_threads()
+            .into_iter()
+            .map(|thread| {
+                let names = thread
+                    .frames
+                    .iter()
+                    .map(|frame| match frame {
+                        Frame::CFrame(frame) => format!("{} {}:{}", frame.func, frame.file, frame.lineno),
+                        Frame::PyFrame(frame) => format!("{} {}:{}", frame.func, frame.file, frame.lineno),
+                        _ => "?".to_string(),
+                    })
+                    .collect();
+                (thread.name, thread.main, names)
+            })
+            .collect()
+    }
+
+    /// py-spy JSON, GDB backtraces and perf script samples become per-thread stacks, innermost first.
+    #[test]
+    fn test_import_dumps() {
+        let pyspy = r#"[{"pid": 42, "thread_id": 1, "os_thread_id": 42, "thread_name": "MainThread", "active": true,
+            "frames": [{"name": "all_reduce", "filename": "/torch/distributed/c10d.py", "line": 2050,
+                        "locals": [{"name": "op", "addr": 1, "arg": true, "repr": "SUM"}]},
+                       {"name": "<module>", "filename": "train.py", "line": 9, "locals": null}]}]"#;
+        assert_eq!(
+            frame_names(import_dump(pyspy, DumpFormat::Auto).expect("Failed to import the py-spy dump")),
+            vec![(
+                Some("MainThread".to_string()),
+                true,
+                vec!["all_reduce /torch/distributed/c10d.py:2050".to_string(), "<module> train.py:9".to_string()]
+            )]
+        );
+
+        let gdb = "\
+Thread 2 (Thread 0x7f3c2e7fc640 (LWP 4243) \"pt_autograd_0\"):
+#0  0x00007f3c4a291117 in __futex_abstimed_wait_common64 (private=0, cancel=true) at ./nptl/futex-internal.c:57
+#1  0x00007f3c3b1d5a2e in std::function<void ()>::operator()() const (this=0x55d1) from /torch/lib/libc10.so
+
+Thread 1 (Thread 0x7f3c4a3b9740 (LWP 4242) \"python\"):
+#0  0x00007f3c4a32399f in poll () from /lib/x86_64-linux-gnu/libc.so.6
+#1  0x000055d1e2b3c9a1 in _PyEval_EvalFrameDefault (tstate=0x55d1, frame=<optimized out>,
+    throwflag=0) at Python/ceval.c:5409
+#2  <signal handler called>
+";
+        assert_eq!(
+            frame_names(import_dump(gdb, DumpFormat::Auto).expect("Failed to import the GDB backtrace")),
+            vec![
+                (
+                    Some("pt_autograd_0".to_string()),
+                    false,
+                    vec![
+                        "__futex_abstimed_wait_common64 ./nptl/futex-internal.c:57".to_string(),
+                        "std::function<void ()>::operator()() const /torch/lib/libc10.so:0".to_string(),
+                    ]
+                ),
+                (
+                    Some("python".to_string()),
+                    true,
+                    vec![
+                        "poll /lib/x86_64-linux-gnu/libc.so.6:0".to_string(),
+                        "_PyEval_EvalFrameDefault Python/ceval.c:5409".to_string(),
+                        "<signal handler called> :0".to_string(),
+                    ]
+                ),
+            ]
+        );
+
+        let perf = "\
+python3 4242/4242 [003] 1234.500000:     250000 cpu-clock:pppH:
+\t    7f3c4a32399f poll+0xf (/lib/x86_64-linux-gnu/libc.so.6)
+
+python3 4242/4242 [003] 1234.750000:     250000 cpu-clock:pppH:
+\t    7f3c4a291117 __futex_abstimed_wait_common64+0x77 (/lib/x86_64-linux-gnu/libc.so.6)
+\t    7f3c3b000010 py::train_step:/workspace/train.py+0x10 ([JIT] tid 4242)
+
+pt_data_worker 4242/4250 [001] 1234.600000:     250000 cpu-clock:pppH:
+\t    7f3c4a32399f [unknown] ([unknown])
+";
+        assert_eq!(
+            frame_names(import_dump(perf, DumpFormat::Perf).expect("Failed to import the perf script output")),
+            vec![
+                (
+                    Some("python3".to_string()),
+                    true,
+                    vec![
+                        "__futex_abstimed_wait_common64 /lib/x86_64-linux-gnu/libc.so.6:0".to_string(),
+                        "train_step /workspace/train.py:0".to_string(),
+                    ]
+                ),
+                (Some("pt_data_worker".to_string()), false, vec!["[unknown] [unknown]:0".to_string()]),
+            ]
+        );
+
+        assert!(import_dump("", DumpFormat::Gdb).is_err());
+        assert_eq!(rank_from_file_name(Path::new("dumps/pyspy_rank-12_20261018.json")), Ok(12));
+        assert_eq!(rank_from_file_name(Path::new("job7/3.txt")), Ok(3));
+        assert!(rank_from_file_name(Path::new("pyspy_20261018.json")).is_err());
+        assert!(rank_from_file_name(Path::new("perf.txt")).is_err());
+    }
+}
@@ -0,0 +1,456 @@
+use base64::Engine;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Directory holding the service account credentials mounted into every pod.
+const SERVICE_ACCOUNT_DIR: &str = "/var/run/secrets/kubernetes.io/serviceaccount";
+
+/// Annotation read first for the rank of a pod.
+pub const RANK_ANNOTATION: &str = "probing.io/rank";
+
+/// Container environment variable read for the rank of a pod when the annotation is missing,
+/// as set by the PyTorchJob and MPIJob operators.
+pub const RANK_ENV: &str = "RANK";
+
+#[derive(Debug, Deserialize)]
+struct KubeConfigFile {
+    #[serde(rename = "current-context")]
+    current_context: Option<String>,
+    #[serde(default)]
+    contexts: Vec<NamedContext>,
+    #[serde(default)]
+    clusters: Vec<NamedCluster>,
+    #[serde(default)]
+    users: Vec<NamedUser>,
+}
+
+#[derive(Debug, Deserialize)]
+struct NamedContext {
+    name: String,
+    context: ContextEntry,
+}
+
+#[derive(Debug, Deserialize)]
+struct ContextEntry {
+    cluster: String,
+    user: Option<String>,
+    namespace: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct NamedCluster {
+    name: String,
+    cluster: ClusterEntry,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct ClusterEntry {
+    server: String,
+    certificate_authority: Option<PathBuf>,
+    certificate_authority_data: Option<String>,
+    #[serde(default)]
+    insecure_skip_tls_verify: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct NamedUser {
+    name: String,
+    user: UserEntry,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct UserEntry {
+    token: Option<String>,
+    #[serde(rename = "tokenFile")]
+    token_file: Option<PathBuf>,
+    client_certificate: Option<PathBuf>,
+    client_certificate_data: Option<String>,
+    client_key: Option<PathBuf>,
+    client_key_data: Option<String>,
+}
+
+/// Reads inline base64 data or, failing that, a file relative to the kubeconfig directory.
+fn read_credential(data: Option<&str>, file: Option<&Path>, base_dir: &Path) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+    if let Some(data) = data {
+        return Ok(Some(base64::engine::general_purpose::STANDARD.decode(data.trim())?));
+    }
+    match file {
+        Some(file) => {
+            let path = base_dir.join(file);
+            let content = std::fs::read(&path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+            Ok(Some(content))
+        }
+        None => Ok(None),
+    }
+}
+
+/// Address and credentials of a Kubernetes API server.
+#[derive(Debug, Default, Clone)]
+pub struct KubeAccess {
+    pub server: String,
+    /// Namespace of the kubeconfig context or of the service account.
+    pub namespace: Option<String>,
+    pub token: Option<String>,
+    /// PEM certificate of the cluster CA.
+    pub ca_pem: Option<Vec<u8>>,
+    /// PEM client certificate and PKCS#8 key.
+    pub client_identity: Option<(Vec<u8>, Vec<u8>)>,
+    pub insecure: bool,
+}
+
+impl KubeAccess {
+    /// Default kubeconfig: `$KUBECONFIG` (its first entry), else `~/.kube/config`.
+    pub fn default_kubeconfig() -> Option<PathBuf> {
+        if let Some(paths) = std::env::var_os("KUBECONFIG") {
+            return std::env::split_paths(&paths).next();
+        }
+        std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".kube").join("config"))
+    }
+
+    /// Reads the cluster and user of `context`, or of the current context, from a kubeconfig.
+    pub fn from_kubeconfig(path: &Path, context: Option<&str>) -> Result<Self, Box<dyn Error>> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read kubeconfig {}: {}", path.display(), e))?;
+        let file: KubeConfigFile =
+            serde_yaml::from_str(&content).map_err(|e| format!("Invalid kubeconfig {}: {}", path.display(), e))?;
+        let base_dir = path.parent().unwrap_or(Path::new("."));
+
+        let context_name = context
+            .map(str::to_string)
+            .or(file.current_context)
+            .ok_or_else(|| format!("No current context in {}, pass --context", path.display()))?;
+        let context = file
+            .contexts
+            .iter()
+            .find(|entry| entry.name == context_name)
+            .map(|entry| &entry.context)
+            .ok_or_else(|| format!("Context '{}' not found in {}", context_name, path.display()))?;
+        let cluster = file
+            .clusters
+            .iter()
+            .find(|entry| entry.name == context.cluster)
+            .map(|entry| &entry.cluster)
+            .ok_or_else(|| format!("Cluster '{}' not found in {}", context.cluster, path.display()))?;
+        let default_user = UserEntry::default();
+        let user = match &context.user {
+            Some(name) => file
+                .users
+                .iter()
+                .find(|entry| &entry.name == name)
+                .map(|entry| &entry.user)
+                .ok_or_else(|| format!("User '{}' not found in {}", name, path.display()))?,
+            None => &default_user,
+        };
+
+        let token = match (&user.token, &user.token_file) {
+            (Some(token), _) => Some(token.clone()),
+            (None, Some(file)) => Some(std::fs::read_to_string(base_dir.join(file))?.trim().to_string()),
+            (None, None) => None,
+        };
+        let certificate = read_credential(
+            user.client_certificate_data.as_deref(),
+            user.client_certificate.as_deref(),
+            base_dir,
+        )?;
+        let key = read_credential(user.client_key_data.as_deref(), user.client_key.as_deref(), base_dir)?;
+        Ok(KubeAccess {
+            server: cluster.server.trim_end_matches('/').to_string(),
+            namespace: context.namespace.clone(),
+            token,
+            ca_pem: read_credential(
+                cluster.certificate_authority_data.as_deref(),
+                cluster.certificate_authority.as_deref(),
+                base_dir,
+            )?,
+            client_identity: certificate.zip(key),
+            insecure: cluster.insecure_skip_tls_verify,
+        })
+    }
+
+    /// Uses the service account of the pod this runs in, if any.
+    pub fn in_cluster() -> Result<Option<Self>, Box<dyn Error>> {
+        let (Ok(host), Ok(port)) = (std::env::var("KUBERNETES_SERVICE_HOST"), std::env::var("KUBERNETES_SERVICE_PORT"))
+        else {
+            return Ok(None);
+        };
+        let dir = Path::new(SERVICE_ACCOUNT_DIR);
+        let host = if host.contains(':') { format!("[{}]", host) } else { host };
+        Ok(Some(KubeAccess {
+            server: format!("https://{}:{}", host, port),
+            namespace: std::fs::read_to_string(dir.join("namespace")).ok().map(|ns| ns.trim().to_string()),
+            token: Some(std::fs::read_to_string(dir.join("token"))?.trim().to_string()),
+            ca_pem: std::fs::read(dir.join("ca.crt")).ok(),
+            client_identity: None,
+            insecure: false,
+        }))
+    }
+
+    fn client(&self, timeout: Duration) -> Result<reqwest::Client, Box<dyn Error>> {
+        let mut builder = reqwest::Client::builder().timeout(timeout);
+        if let Some(ca) = &self.ca_pem {
+            builder = builder.add_root_certificate(reqwest::Certificate::from_pem(ca)?);
+        }
+        if let Some((certificate, key)) = &self.client_identity {
+            let identity = reqwest::Identity::from_pkcs8_pem(certificate, key)
+                .map_err(|e| format!("Unsupported client certificate, the key must be PKCS#8 PEM: {}", e))?;
+            builder = builder.identity(identity);
+        }
+        if self.insecure {
+            builder = builder.danger_accept_invalid_certs(true);
+        }
+        Ok(builder.build()?)
+    }
+
+    /// Lists the pods of `namespace` matching a label selector such as `training.kubeflow.org/job-name=llama`.
+    pub async fn list_pods(&self, namespace: &str, selector: &str, timeout: Duration) -> Result<Vec<Pod>, Box<dyn Error>> {
+        let url = format!("{}/api/v1/namespaces/{}/pods", self.server, namespace);
+        let mut request = self.client(timeout)?.get(&url).query(&[("labelSelector", selector)]);
+        if let Some(token) = &self.token {
+            request = request.bearer_auth(token);
+        }
+        let response = request.send().await?;
+        let status = response.status();
+        if !status.is_success() {
+            return Err(format!("Listing pods at {} failed with {}: {}", url, status, response.text().await?).into());
+        }
+        let list: PodList = response.json().await?;
+        Ok(list.items)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct PodList {
+    #[serde(default)]
+    items: Vec<Pod>,
+}
+
+/// The fields of a pod used to locate its Probing endpoint.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Pod {
+    pub metadata: PodMetadata,
+    #[serde(default)]
+    pub spec: PodSpec,
+    #[serde(default)]
+    pub status: PodStatus,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct PodMetadata {
+    pub name: String,
+    #[serde(default)]
+    pub annotations: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PodSpec {
+    pub node_name: Option<String>,
+    #[serde(default)]
+    pub containers: Vec<Container>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Container {
+    #[serde(default)]
+    pub env: Vec<EnvVar>,
+}
+
+/// A container environment variable; values taken from references are not resolved.
+#[derive(Debug, Clone, Deserialize)]
+pub struct EnvVar {
+    pub name: String,
+    pub value: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PodStatus {
+    #[serde(rename = "podIP")]
+    pub pod_ip: Option<String>,
+    /// `Pending`, `Running`, `Succeeded`, `Failed` or `Unknown`.
+    pub phase: Option<String>,
+}
+
+impl Pod {
+    /// Rank of the pod, read from `annotation`, else from the `env` variable of its containers.
+    /// An annotation that is not a number is reported and the env variable used instead.
+    pub fn rank(&self, annotation: &str, env: &str) -> Option<u32> {
+        if let Some(rank) = self.metadata.annotations.get(annotation) {
+            match rank.trim().parse() {
+                Ok(rank) => return Some(rank),
+                Err(e) => eprintln!(
+                    "Warning: Invalid rank '{}' in annotation {} of pod {}: {}, trying env {}",
+                    rank, annotation, self.metadata.name, e, env
+                ),
+            }
+        }
+        self.spec
+            .containers
+            .iter()
+            .flat_map(|container| &container.env)
+            .find(|var| var.name == env)
+            .and_then(|var| var.value.as_deref()?.trim().parse().ok())
+    }
+}
+
+/// How the ranks of the discovered pods are numbered and where their endpoints listen.
+#[derive(Debug, Clone)]
+pub struct PodRankOptions {
+    pub rank_annotation: String,
+    pub rank_env: String,
+    /// Training processes per pod. Above 1, the rank read from a pod is its node rank and
+    /// its processes get ranks `node_rank * procs_per_pod + local_rank`.
+    pub procs_per_pod: u32,
+    /// Port of the first process in each pod; the others listen on the following ports.
+    pub base_port: u16,
+}
+
+/// A rank found in a pod.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PodTarget {
+    pub rank: u32,
+    /// `ip:port` of the rank's Probing endpoint.
+    pub address: String,
+    pub pod: String,
+    /// Kubernetes node the pod is scheduled on.
+    pub node: Option<String>,
+}
+
+/// Builds the rank → endpoint map of the pods, skipping with a warning the pods that are not running
+/// or have no IP or rank.
+pub fn pod_targets(pods: &[Pod], options: &PodRankOptions) -> Result<Vec<PodTarget>, String> {
+    let mut targets: BTreeMap<u32, PodTarget> = BTreeMap::new();
+    for pod in pods {
+        let name = &pod.metadata.name;
+        let phase = pod.status.phase.as_deref().unwrap_or("Unknown");
+        if phase != "Running" {
+            eprintln!("Warning: Pod {} is {}, skipping it", name, phase);
+            continue;
+        }
+        let Some(ip) = &pod.status.pod_ip else {
+            eprintln!("Warning: Pod {} has no IP yet, skipping it", name);
+            continue;
+        };
+        let Some(pod_rank) = pod.rank(&options.rank_annotation, &options.rank_env) else {
+            eprintln!(
+                "Warning: No rank in annotation {} or env {} of pod {}, skipping it",
+                options.rank_annotation, options.rank_env, name
+            );
+            continue;
+        };
+        let host = if ip.contains(':') { format!("[{}]", ip) } else { ip.clone() };
+        for local_rank in 0..options.procs_per_pod {
+            let rank = pod_rank * options.procs_per_pod + local_rank;
+            let port = u16::try_from(options.base_port as u32 + local_rank)
+                .map_err(|_| format!("Port out of range for local rank {} of pod {}", local_rank, name))?;
+            let target = PodTarget {
+                rank,
+                address: format!("{}:{}", host, port),
+                pod: name.clone(),
+                node: pod.spec.node_name.clone(),
+            };
+            if let Some(other) = targets.insert(rank, target) {
+                return Err(format!("Rank {} found in both pod {} and pod {}", rank, other.pod, name));
+            }
+        }
+    }
+    Ok(targets.into_values().collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    /// Pods listed from a mock API server through a kubeconfig map to ranks by annotation or env.
+    #[tokio::test]
+    async fn test_pod_discovery_against_mock_api_server() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.expect("Failed to bind mock API server");
+        let port = listener.local_addr().expect("No local address").port();
+        let server = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.expect("No request");
+            let mut request = Vec::new();
+            let mut buffer = [0; 4096];
+            while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+                let read = socket.read(&mut buffer).await.expect("Failed to read request");
+                request.extend_from_slice(&buffer[..read]);
+            }
+            let body = r#"{"kind": "PodList", "items": [
+                {"metadata": {"name": "llama-worker-1", "annotations": {"probing.io/rank": "1"}},
+                 "spec": {"nodeName": "gpu002", "containers": [{"env": [{"name": "RANK", "value": "7"}]}]},
+                 "status": {"podIP": "10.1.0.6", "phase": "Running"}},
+                {"metadata": {"name": "llama-master-0"},
+                 "spec": {"nodeName": "gpu001", "containers": [{"env": [{"name": "RANK", "value": "0"}]}]},
+                 "status": {"podIP": "10.1.0.5", "phase": "Running"}},
+                {"metadata": {"name": "llama-worker-2"},
+                 "spec": {"containers": [{"env": [{"name": "RANK", "value": "2"}]}]},
+                 "status": {"phase": "Pending"}},
+                {"metadata": {"name": "llama-worker-1-old", "annotations": {"probing.io/rank": "1"}},
+                 "status": {"podIP": "10.1.0.4", "phase": "Failed"}},
+                {"metadata": {"name": "llama-worker-3", "annotations": {"probing.io/rank": "three"}},
+                 "spec": {"containers": [{"env": [{"name": "RANK", "value": "3"}]}]},
+                 "status": {"podIP": "10.1.0.7", "phase": "Running"}}
+            ]}"#;
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            );
+            socket.write_all(response.as_bytes()).await.expect("Failed to write response");
+            String::from_utf8_lossy(&request).to_string()
+        });
+
+        let kubeconfig = std::env::temp_dir().join(format!("flame_test_kubeconfig_{}", std::process::id()));
+        std::fs::write(
+            &kubeconfig,
+            format!(
+                "apiVersion: v1\nkind: Config\ncurrent-context: mock\n\
+                 contexts:\n- name: mock\n  context: {{cluster: mock, user: trainer, namespace: training}}\n\
+                 clusters:\n- name: mock\n  cluster: {{server: 'http://127.0.0.1:{}'}}\n\
+                 users:\n- name: trainer\n  user: {{token: secret-token}}\n",
+                port
+            ),
+        )
+        .expect("Failed to write kubeconfig");
+
+        let access = KubeAccess::from_kubeconfig(&kubeconfig, None).expect("Invalid kubeconfig");
+        assert_eq!(access.namespace.as_deref(), Some("training"));
+        let pods = access
+            .list_pods("training", "training.kubeflow.org/job-name=llama", Duration::from_secs(5))
+            .await
+            .expect("Failed to list pods");
+
+        let request = server.await.expect("Mock API server failed");
+        assert!(request.starts_with(
+            "GET /api/v1/namespaces/training/pods?labelSelector=training.kubeflow.org%2Fjob-name%3Dllama HTTP/1.1"
+        ));
+        assert!(request.to_lowercase().contains("authorization: bearer secret-token"));
+
+        let options = PodRankOptions {
+            rank_annotation: RANK_ANNOTATION.to_string(),
+            rank_env: RANK_ENV.to_string(),
+            procs_per_pod: 2,
+            base_port: 11490,
+        };
+        let targets = pod_targets(&pods, &options).expect("Distinct ranks");
+        let addresses: Vec<(u32, &str)> = targets.iter().map(|target| (target.rank, target.address.as_str())).collect();
+        assert_eq!(
+            addresses,
+            vec![
+                (0, "10.1.0.5:11490"),
+                (1, "10.1.0.5:11491"),
+                (2, "10.1.0.6:11490"),
+                (3, "10.1.0.6:11491"),
+                (6, "10.1.0.7:11490"),
+                (7, "10.1.0.7:11491"),
+            ]
+        );
+        assert_eq!(targets[2].node.as_deref(), Some("gpu002"));
+    }
+}
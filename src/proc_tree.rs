@@ -0,0 +1,217 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::Path;
+
+/// One process of the `/proc` table.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProcEntry {
+    pub pid: u32,
+    pub ppid: u32,
+    /// Executable name, from the parentheses of `/proc/<pid>/stat`.
+    pub comm: String,
+}
+
+/// Parses `/proc/<pid>/stat`. The name may itself contain spaces and parentheses,
+/// so the fields are split after its last closing parenthesis.
+pub fn parse_stat(content: &str) -> Option<ProcEntry> {
+    let (head, tail) = content.rsplit_once(')')?;
+    let (pid, comm) = head.split_once(" (")?;
+    // state ppid ...
+    let ppid = tail.split_whitespace().nth(1)?.parse().ok()?;
+    Some(ProcEntry { pid: pid.trim().parse().ok()?, ppid, comm: comm.to_string() })
+}
+
+/// Reads every process of the `/proc` file system mounted at `proc_root`, skipping the ones
+/// exiting while it is read.
+pub fn read_processes(proc_root: &Path) -> std::io::Result<Vec<ProcEntry>> {
+    let mut processes = Vec::new();
+    for entry in fs::read_dir(proc_root)?.filter_map(Result::ok) {
+        let name = entry.file_name();
+        if !name.to_string_lossy().chars().all(|c| c.is_ascii_digit()) {
+            continue;
+        }
+        if let Some(process) = fs::read_to_string(entry.path().join("stat")).ok().as_deref().and_then(parse_stat) {
+            processes.push(process);
+        }
+    }
+    processes.sort_by_key(|process| process.pid);
+    Ok(processes)
+}
+
+/// Returns the descendants of `pid` in PID order, i.e. the order they were started in,
+/// which numbers the DataLoader workers of a rank like PyTorch does.
+pub fn descendants(processes: &[ProcEntry], pid: u32) -> Vec<u32> {
+    let mut found = BTreeSet::new();
+    let mut parents = vec![pid];
+    while let Some(parent) = parents.pop() {
+        for process in processes.iter().filter(|process| process.ppid == parent) {
+            if found.insert(process.pid) {
+                parents.push(process.pid);
+            }
+        }
+    }
+    found.into_iter().collect()
+}
+
+/// Reads the environment of a process from `/proc/<pid>/environ`.
+pub fn read_environ(proc_root: &Path, pid: u32) -> BTreeMap<String, String> {
+    let content = fs::read(proc_root.join(pid.to_string()).join("environ")).unwrap_or_default();
+    String::from_utf8_lossy(&content)
+        .split('\0')
+        .filter_map(|line| line.split_once('='))
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect()
+}
+
+/// Decodes an address of `/proc/net/tcp{,6}`, e.g. `0100007F:2CE2`; the IP is stored
+/// as 32-bit words in host (little-endian) byte order.
+fn parse_socket_address(field: &str) -> Option<SocketAddr> {
+    let (ip, port) = field.split_once(':')?;
+    let port = u16::from_str_radix(port, 16).ok()?;
+    let words: Vec<u32> = (0..ip.len() / 8)
+        .map(|i| u32::from_str_radix(ip.get(i * 8..i * 8 + 8)?, 16).ok())
+        .collect::<Option<_>>()?;
+    let ip = match words.as_slice() {
+        [word] => IpAddr::V4(Ipv4Addr::from(word.to_le_bytes())),
+        [a, b, c, d] => {
+            let mut octets = [0u8; 16];
+            for (chunk, word) in octets.chunks_mut(4).zip([a, b, c, d]) {
+                chunk.copy_from_slice(&word.to_le_bytes());
+            }
+            IpAddr::V6(Ipv6Addr::from(octets))
+        }
+        _ => return None,
+    };
+    Some(SocketAddr::new(ip, port))
+}
+
+/// TCP state of a listening socket in `/proc/net/tcp`.
+const TCP_LISTEN: &str = "0A";
+
+/// Returns the TCP addresses `pid` listens on: its socket file descriptors matched against the
+/// listening sockets of its network namespace.
+pub fn listening_addresses(proc_root: &Path, pid: u32) -> Vec<SocketAddr> {
+    let dir = proc_root.join(pid.to_string());
+    let inodes: BTreeSet<String> = fs::read_dir(dir.join("fd"))
+        .map(|entries| {
+            entries
+                .filter_map(Result::ok)
+                .filter_map(|entry| fs::read_link(entry.path()).ok())
+                .filter_map(|target| {
+                    let target = target.to_string_lossy();
+                    Some(target.strip_prefix("socket:[")?.strip_suffix(']')?.to_string())
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+
+    let mut addresses = Vec::new();
+    for table in ["net/tcp", "net/tcp6"] {
+        let content = fs::read_to_string(dir.join(table)).unwrap_or_default();
+        for line in content.lines().skip(1) {
+            // sl local_address rem_address st tx_queue:rx_queue tr:tm->when retrnsmt uid timeout inode
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() > 9
+                && fields[3] == TCP_LISTEN
+                && inodes.contains(fields[9])
+                && let Some(address) = parse_socket_address(fields[1])
+            {
+                addresses.push(address);
+            }
+        }
+    }
+    addresses.sort();
+    addresses.dedup();
+    addresses
+}
+
+/// A training process found on this machine, with its child processes.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LocalRank {
+    /// `RANK` from the environment of the process.
+    pub rank: u32,
+    pub pid: u32,
+    /// Descendants of the process, such as DataLoader workers and checkpoint writers, in PID order.
+    pub workers: Vec<u32>,
+}
+
+/// Finds the training processes of this machine: processes with a `RANK` environment variable
+/// whose parent does not have the same one, as their children inherit it.
+pub fn local_ranks(proc_root: &Path) -> std::io::Result<Vec<LocalRank>> {
+    let processes = read_processes(proc_root)?;
+    let ranks: BTreeMap<u32, u32> = processes
+        .iter()
+        .filter_map(|process| {
+            let rank = read_environ(proc_root, process.pid).get("RANK")?.trim().parse().ok()?;
+            Some((process.pid, rank))
+        })
+        .collect();
+
+    let mut local = Vec::new();
+    for process in &processes {
+        let Some(&rank) = ranks.get(&process.pid) else { continue };
+        if ranks.get(&process.ppid) == Some(&rank) {
+            continue;
+        }
+        local.push(LocalRank { rank, pid: process.pid, workers: descendants(&processes, process.pid) });
+    }
+    local.sort_by_key(|local| local.rank);
+    Ok(local)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+
+    /// Ranks are told apart from their workers, which inherit `RANK`, and each process gets
+    /// the ports of its own listening sockets.
+    #[test]
+    fn test_local_ranks_and_listening_addresses() {
+        let root = std::env::temp_dir().join(format!("flame_test_proc_tree_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        let process = |pid: u32, ppid: u32, comm: &str, env: &str, sockets: &[u32]| {
+            let dir = root.join(pid.to_string());
+            fs::create_dir_all(dir.join("fd")).expect("Failed to create fd directory");
+            fs::create_dir_all(dir.join("net")).expect("Failed to create net directory");
+            fs::write(dir.join("stat"), format!("{} ({}) S {} 1 1 0 -1", pid, comm, ppid)).expect("Failed to write stat");
+            fs::write(dir.join("environ"), env.replace(' ', "\0")).expect("Failed to write environ");
+            for (fd, inode) in sockets.iter().enumerate() {
+                symlink(format!("socket:[{}]", inode), dir.join("fd").join(fd.to_string())).expect("Failed to create socket link");
+            }
+            fs::write(
+                dir.join("net/tcp"),
+                "  sl  local_address rem_address   st tx_queue rx_queue tr tm->when retrnsmt   uid  timeout inode\n\
+                 \x20  0: 470CCB0A:2CE2 00000000:0000 0A 00000000:00000000 00:00000000 00000000     0        0 5001 1\n\
+                 \x20  1: 0100007F:D431 00000000:0000 0A 00000000:00000000 00:00000000 00000000     0        0 5002 1\n\
+                 \x20  2: 470CCB0A:2CE2 470CCB0B:9C40 01 00000000:00000000 00:00000000 00000000     0        0 5003 1\n",
+            )
+            .expect("Failed to write net/tcp");
+        };
+        process(1, 0, "systemd", "", &[]);
+        process(100, 1, "torchrun", "PATH=/usr/bin", &[]);
+        process(200, 100, "python (train)", "RANK=8 LOCAL_RANK=0", &[5001, 5003]);
+        process(210, 200, "pt_data_worker", "RANK=8 LOCAL_RANK=0", &[5001, 5002]);
+        process(205, 200, "pt_data_worker", "RANK=8 LOCAL_RANK=0", &[]);
+        process(300, 100, "python", "RANK=9", &[]);
+
+        assert_eq!(
+            parse_stat("200 (python (train)) S 100 1 1").map(|entry| (entry.ppid, entry.comm)),
+            Some((100, "python (train)".to_string()))
+        );
+        assert_eq!(
+            local_ranks(&root).expect("Failed to read the process tree"),
+            vec![
+                LocalRank { rank: 8, pid: 200, workers: vec![205, 210] },
+                LocalRank { rank: 9, pid: 300, workers: vec![] },
+            ]
+        );
+        assert_eq!(listening_addresses(&root, 200), vec!["10.203.12.71:11490".parse().expect("Valid socket address")]);
+        assert_eq!(
+            listening_addresses(&root, 210),
+            vec!["10.203.12.71:11490".parse().expect("Valid socket address"), "127.0.0.1:54321".parse().expect("Valid socket address")]
+        );
+        assert_eq!(parse_socket_address("00000000000000000000000001000000:2CE2"), Some("[::1]:11490".parse().expect("Valid socket address")));
+    }
+}
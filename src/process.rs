@@ -1,40 +1,599 @@
 use chrono::Local;
+use regex::RegexSet;
 use serde::{Deserialize, Serialize};
-use std::collections::{HashMap, BTreeSet}; // 新增 BTreeSet 导入
+use std::collections::{BTreeMap, BTreeSet, HashMap}; // 新增 BTreeSet 导入
 use std::error::Error;
 use std::fs::File;
-use std::io::{Read, Write};
-use serde_json;
-use std::path::PathBuf;
-use std::env;
+use std::io::Write;
+use std::path::{Path, PathBuf};
 
-use reqwest;
-use serde_json::Value;
-use futures::future::join_all; 
+use crate::analysis::detect_step_skew;
+use crate::frame_meta::{FrameCategory, FrameMeta};
+use crate::process_info::ProcessInfo;
+use crate::symbolize::{symbolize_frames, SymbolizeOptions};
 
-/// Represents a frame in the call stack, which can be either a C frame or a Python frame.
+/// Represents a frame in the call stack: a C frame, a Python frame, or a suspended coroutine of an asyncio task.
+/// Frames of other kinds, e.g. from newer Probing versions, are kept as their raw JSON.
 #[derive(Debug, Deserialize, Serialize, Clone)]
-enum Frame {
+#[allow(clippy::enum_variant_names)] // 变体名即Probing返回的JSON键
+pub(crate) enum Frame {
     CFrame(CFrame),
     PyFrame(PyFrame),
+    CoroFrame(CoroFrame),
+    #[serde(untagged)]
+    Unknown(serde_json::Value),
+}
+
+/// Reads a field that is `null` like a missing one, as its default value.
+fn nullable<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: Default + Deserialize<'de>,
+{
+    Ok(Option::<T>::deserialize(deserializer)?.unwrap_or_default())
 }
 
 /// Represents a C frame in the call stack.
 #[derive(Debug, Deserialize, Serialize, Clone)]
-struct CFrame {
-    file: String,
-    func: String,
-    ip: String,
-    lineno: u32,
+pub(crate) struct CFrame {
+    #[serde(default, deserialize_with = "nullable")]
+    pub(crate) file: String,
+    #[serde(default, deserialize_with = "nullable")]
+    pub(crate) func: String,
+    #[serde(default, deserialize_with = "nullable")]
+    pub(crate) ip: String,
+    #[serde(default, deserialize_with = "nullable")]
+    pub(crate) lineno: u32,
 }
 
 /// Represents a Python frame in the call stack.
 #[derive(Debug, Deserialize, Serialize, Clone)]
-struct PyFrame {
+pub(crate) struct PyFrame {
+    #[serde(default, deserialize_with = "nullable")]
+    pub(crate) file: String,
+    #[serde(default, deserialize_with = "nullable")]
+    pub(crate) func: String,
+    #[serde(default, deserialize_with = "nullable")]
+    pub(crate) lineno: u32,
+    #[serde(default)]
+    pub(crate) locals: serde_json::Value,
+}
+
+/// Represents a suspended coroutine of an asyncio task, one link of its await chain.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub(crate) struct CoroFrame {
+    #[serde(default, deserialize_with = "nullable")]
     file: String,
+    #[serde(default, deserialize_with = "nullable")]
     func: String,
+    #[serde(default, deserialize_with = "nullable")]
     lineno: u32,
-    locals: serde_json::Value,
+}
+
+impl Frame {
+    /// Kind of a frame read as `Unknown`: the key of its `{"Kind": {...}}` object.
+    fn unknown_kind(raw: &serde_json::Value) -> &str {
+        match raw {
+            serde_json::Value::Object(map) if map.len() == 1 => map.keys().next().map(String::as_str).unwrap_or("unknown"),
+            _ => "unknown",
+        }
+    }
+}
+
+/// Names a frame of an unknown kind after the `func`/`file`/`lineno` fields it shares with the known
+/// kinds, e.g. `launch (kernels.cu:12)`, or after its kind, e.g. `[GpuFrame]`.
+fn unknown_frame_name(raw: &serde_json::Value) -> String {
+    let kind = Frame::unknown_kind(raw);
+    let fields = match raw {
+        serde_json::Value::Object(map) if map.len() == 1 => map.values().next().unwrap_or(raw),
+        _ => raw,
+    };
+    let text = |key: &str| match fields.get(key)? {
+        serde_json::Value::String(value) => Some(value.clone()),
+        serde_json::Value::Number(value) => Some(value.to_string()),
+        _ => None,
+    };
+    let name = match text("func").or_else(|| text("name")) {
+        Some(func) => format!(
+            "{} ({}:{})",
+            func,
+            text("file").unwrap_or_default(),
+            text("lineno").unwrap_or_else(|| "0".to_string())
+        ),
+        None => format!("[{}]", kind),
+    };
+    name.replace(';', ",")
+}
+
+impl PyFrame {
+    /// Returns the display value of a local variable, if the frame captured it.
+    pub(crate) fn local(&self, name: &str) -> Option<String> {
+        match &self.locals {
+            serde_json::Value::Object(map) => map.get(name).map(local_value_to_string),
+            _ => None,
+        }
+    }
+}
+
+/// Call stack of one thread of a training process.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub(crate) struct ThreadStack {
+    #[serde(default, alias = "tid", alias = "thread_id")]
+    pub(crate) id: Option<u64>,
+    #[serde(default, alias = "thread_name")]
+    pub(crate) name: Option<String>,
+    /// The interpreter's main thread.
+    #[serde(default, alias = "is_main")]
+    pub(crate) main: bool,
+    /// Frames of the thread, innermost first. Required, as it tells a thread from a frame.
+    #[serde(deserialize_with = "nullable")]
+    pub(crate) frames: Vec<Frame>,
+    /// Pending asyncio tasks of the event loop running in this thread.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) tasks: Vec<TaskStack>,
+}
+
+/// An asyncio task and the await chain of its coroutines.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub(crate) struct TaskStack {
+    #[serde(default, alias = "task_name")]
+    pub(crate) name: Option<String>,
+    /// Qualified name of the coroutine the task wraps.
+    #[serde(default, alias = "coroutine")]
+    pub(crate) coro: Option<String>,
+    /// Coroutine frames of the await chain, innermost first.
+    #[serde(default, deserialize_with = "nullable")]
+    pub(crate) frames: Vec<Frame>,
+}
+
+impl TaskStack {
+    /// Label merging the same task across ranks: its name, or for the `Task-<n>` names asyncio
+    /// numbers tasks with, its coroutine.
+    fn label(&self) -> String {
+        let name = self.name.as_deref().unwrap_or_default();
+        let numbered = name.strip_prefix("Task-").is_some_and(|n| !n.is_empty() && n.chars().all(|c| c.is_ascii_digit()));
+        let label = if !name.is_empty() && !numbered {
+            name.to_string()
+        } else if let Some(coro) = &self.coro {
+            coro.clone()
+        } else {
+            match self.frames.last() {
+                Some(Frame::CoroFrame(CoroFrame { func, .. }) | Frame::PyFrame(PyFrame { func, .. })) => func.clone(),
+                _ => "Task".to_string(),
+            }
+        };
+        label.replace(';', ",")
+    }
+}
+
+impl ThreadStack {
+    /// Role of the thread guessed from its name, so that the same kind of thread merges across ranks.
+    fn role(&self) -> &'static str {
+        let name = self.name.as_deref().unwrap_or_default().to_ascii_lowercase();
+        if self.main || name == "mainthread" {
+            "main"
+        } else if name.contains("watchdog") || name.contains("nccl") || name.contains("processgroup") {
+            "nccl-watchdog"
+        } else if name.contains("pin_memory") || name.contains("dataloader") || name.contains("data_loader") {
+            "dataloader"
+        } else if name.contains("checkpoint") || name.contains("ckpt") {
+            "checkpoint"
+        } else if name.contains("autograd") {
+            "autograd"
+        } else {
+            "other"
+        }
+    }
+}
+
+/// Call stacks fetched from one rank: the stack of its main thread, as returned by older
+/// Probing versions, or the stacks of all of its threads, plus those of its worker processes.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(untagged)]
+pub(crate) enum RankStacks {
+    Threads {
+        threads: Vec<ThreadStack>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        workers: Vec<WorkerStacks>,
+        /// Set by `attach_workers` when `threads` only wraps the single stack of an older Probing version.
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        single: bool,
+    },
+    ThreadList(Vec<ThreadStack>),
+    Single(Vec<Frame>),
+    /// Stacks that could not be read, with the reason; the rank is merged without them.
+    #[serde(skip)]
+    Invalid(String),
+}
+
+/// Call stacks of a child process of a rank, such as a DataLoader worker.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub(crate) struct WorkerStacks {
+    /// Index of the worker among the children of its rank, in PID order.
+    pub(crate) worker: u32,
+    pub(crate) stacks: RankStacks,
+}
+
+impl RankStacks {
+    /// Returns the threads of the rank; a single stack is its main thread.
+    pub(crate) fn into_threads(self) -> Vec<ThreadStack> {
+        self.into_parts().0
+    }
+
+    /// Splits the rank into its own threads and its workers.
+    fn into_parts(self) -> (Vec<ThreadStack>, Vec<WorkerStacks>) {
+        match self {
+            RankStacks::Threads { threads, workers, .. } => (threads, workers),
+            RankStacks::ThreadList(threads) => (threads, Vec::new()),
+            RankStacks::Single(frames) => {
+                (vec![ThreadStack { id: None, name: None, main: true, frames, tasks: Vec::new() }], Vec::new())
+            }
+            RankStacks::Invalid(_) => (Vec::new(), Vec::new()),
+        }
+    }
+
+    /// Describes the parts of the rank's stacks this version could not read.
+    pub(crate) fn schema_warnings(&self) -> Vec<String> {
+        if let RankStacks::Invalid(reason) = self {
+            return vec![format!("unreadable call stacks, merged without them: {}", reason)];
+        }
+        let mut unknown: BTreeMap<String, usize> = BTreeMap::new();
+        self.visit_frames(&mut |frame| {
+            if let Frame::Unknown(raw) = frame {
+                *unknown.entry(Frame::unknown_kind(raw).to_string()).or_default() += 1;
+            }
+        });
+        unknown
+            .into_iter()
+            .map(|(kind, count)| format!("{} '{}' frames of an unknown kind or layout merged as generic frames", count, kind))
+            .collect()
+    }
+
+    /// Calls `visit` on every frame of the threads, tasks and workers.
+    fn visit_frames(&self, visit: &mut impl FnMut(&Frame)) {
+        let threads = match self {
+            RankStacks::Threads { threads, workers, .. } => {
+                for worker in workers {
+                    worker.stacks.visit_frames(visit);
+                }
+                threads
+            }
+            RankStacks::ThreadList(threads) => threads,
+            RankStacks::Single(frames) => {
+                frames.iter().for_each(visit);
+                return;
+            }
+            RankStacks::Invalid(_) => return,
+        };
+        for thread in threads {
+            thread.frames.iter().for_each(&mut *visit);
+            for task in &thread.tasks {
+                task.frames.iter().for_each(&mut *visit);
+            }
+        }
+    }
+
+    /// Whether this dump or one of its workers has the stacks of all threads.
+    fn has_threads(&self) -> bool {
+        !self.is_single()
+            || matches!(self, RankStacks::Threads { workers, .. } if workers.iter().any(|worker| worker.stacks.has_threads()))
+    }
+
+    /// Whether the rank only has the stack of its main thread, as returned by older Probing versions,
+    /// possibly wrapped by `attach_workers`.
+    fn is_single(&self) -> bool {
+        match self {
+            RankStacks::Single(_) | RankStacks::Invalid(_) => true,
+            RankStacks::Threads { single, .. } => *single,
+            RankStacks::ThreadList(_) => false,
+        }
+    }
+
+    /// Number of frames over all threads, tasks and workers.
+    pub(crate) fn frame_count(&self) -> usize {
+        let mut count = 0;
+        self.visit_frames(&mut |_| count += 1);
+        count
+    }
+
+    /// Number of frames of a kind this version knows, over all threads, tasks and workers.
+    pub(crate) fn known_frame_count(&self) -> usize {
+        let mut count = 0;
+        self.visit_frames(&mut |frame| {
+            if !matches!(frame, Frame::Unknown(_)) {
+                count += 1;
+            }
+        });
+        count
+    }
+}
+
+/// Adds the fetched call stacks of the workers of a rank, given as `(worker, stacks)`, to the
+/// call stacks of the rank, turning them into the `{"threads": ..., "workers": ...}` form.
+pub(crate) fn attach_workers(rank_stacks: serde_json::Value, workers: Vec<(u32, serde_json::Value)>) -> serde_json::Value {
+    if workers.is_empty() {
+        return rank_stacks;
+    }
+    let mut object = match rank_stacks {
+        serde_json::Value::Object(object) => object,
+        // 所有线程的调用栈
+        serde_json::Value::Array(items) if items.iter().any(|item| item.get("frames").is_some()) => {
+            serde_json::Map::from_iter([("threads".to_string(), serde_json::Value::Array(items))])
+        }
+        // 旧版本Probing只返回主线程调用栈
+        frames => serde_json::Map::from_iter([
+            ("threads".to_string(), serde_json::json!([{"main": true, "frames": frames}])),
+            ("single".to_string(), serde_json::Value::Bool(true)),
+        ]),
+    };
+    let workers = workers
+        .into_iter()
+        .map(|(worker, stacks)| serde_json::json!({"worker": worker, "stacks": stacks}))
+        .collect();
+    object.insert("workers".to_string(), serde_json::Value::Array(workers));
+    serde_json::Value::Object(object)
+}
+
+/// Returns the index of the main thread: the one flagged `main`, else the one named `MainThread`, else the first.
+fn main_thread_index(threads: &[ThreadStack]) -> usize {
+    threads
+        .iter()
+        .position(|thread| thread.main)
+        .or_else(|| threads.iter().position(|thread| thread.name.as_deref() == Some("MainThread")))
+        .unwrap_or(0)
+}
+
+/// Parses the collected JSON array of per-rank call stacks (innermost frame first). The stacks of
+/// each rank are read on their own, so a rank this version cannot read becomes `RankStacks::Invalid`
+/// rather than failing the whole collection.
+pub(crate) fn parse_callstacks(json_data: &str) -> Result<Vec<RankStacks>, serde_json::Error> {
+    let ranks: Vec<serde_json::Value> = serde_json::from_str(json_data)?;
+    Ok(ranks
+        .into_iter()
+        .map(|raw| {
+            RankStacks::deserialize(&raw).unwrap_or_else(|e| {
+                let mut preview = raw.to_string();
+                if preview.len() > 80 {
+                    preview = format!("{}...", preview.chars().take(80).collect::<String>());
+                }
+                RankStacks::Invalid(format!("{} in {}", e, preview))
+            })
+        })
+        .collect())
+}
+
+/// How the threads of each rank are merged.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum ThreadMode {
+    /// Group the threads under their role (main, nccl-watchdog, dataloader, checkpoint, autograd, other),
+    /// merging the same role across ranks.
+    #[default]
+    Role,
+    /// Group the threads under their name.
+    Name,
+    /// Group the threads under their name and thread ID, keeping every thread apart.
+    Id,
+    /// Keep only the main thread, without a thread level.
+    Main,
+}
+
+impl ThreadMode {
+    /// Trie level naming `thread`, or `None` to leave the thread out. `single` marks the stack of
+    /// a single-stack dump of an older Probing version, which is the main thread: it gets no level
+    /// unless `threaded`, i.e. another dump of the same merge has all its threads, so that the main
+    /// stacks of all ranks end up under the same level.
+    fn level(self, thread: &ThreadStack, single: bool, threaded: bool, is_main: bool) -> Option<Vec<String>> {
+        if single && !threaded {
+            return Some(Vec::new());
+        }
+        let unnamed = if single { "MainThread" } else { "unnamed" };
+        let name = || thread.name.as_deref().unwrap_or(unnamed).replace(';', ",");
+        let label = match self {
+            ThreadMode::Main => return is_main.then(Vec::new),
+            ThreadMode::Role => thread.role().to_string(),
+            ThreadMode::Name => name(),
+            ThreadMode::Id => match thread.id {
+                Some(id) => format!("{} {}", name(), id),
+                None => name(),
+            },
+        };
+        Some(vec![format!("[thread {}]", label)])
+    }
+}
+
+/// Options controlling how per-rank call stacks are merged.
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct MergeOptions {
+    /// Names of Python local variables to summarize per frame (e.g. `step`, `iteration`).
+    pub locals: Vec<String>,
+    /// Local variable holding the training step or iteration, compared across ranks.
+    pub step_var: Option<String>,
+    /// Offline symbolization of native frames lacking source locations.
+    pub symbolize: SymbolizeOptions,
+    /// Re-root the merged stacks at their leaf frames for an icicle view.
+    pub icicle: bool,
+    /// Node (host) of each rank, taken from the target addresses.
+    pub rank_nodes: BTreeMap<u32, String>,
+    /// Frames dropped or cut while merging.
+    pub filter: FrameFilter,
+    /// How the threads of each rank are merged.
+    pub threads: ThreadMode,
+    /// Process metadata of each rank, fetched next to its call stack. Saved in the frame
+    /// annotations rather than with the settings.
+    #[serde(skip)]
+    pub processes: BTreeMap<u32, ProcessInfo>,
+    /// Local time the stacks were captured, when they were not just fetched, e.g. for `merge --raw`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub captured_at: Option<String>,
+}
+
+/// Rules dropping frames while merging, given as regular expressions matched against
+/// frame names such as `train_step (/workspace/train.py:42)`.
+#[derive(Debug, Default, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FrameFilter {
+    /// Frames left out of the merged stacks, e.g. `^_PyEval_` for interpreter internals.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub exclude: Vec<String>,
+    /// Frames at which a stack is cut, dropping them and everything deeper.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub truncate_at: Vec<String>,
+}
+
+impl FrameFilter {
+    /// Fills the empty rule lists of `self` from `base`.
+    pub fn or(self, base: FrameFilter) -> FrameFilter {
+        FrameFilter {
+            exclude: if self.exclude.is_empty() { base.exclude } else { self.exclude },
+            truncate_at: if self.truncate_at.is_empty() { base.truncate_at } else { self.truncate_at },
+        }
+    }
+
+    /// Compiles the rules, once per merge.
+    fn compile(&self) -> Result<CompiledFilter, regex::Error> {
+        Ok(CompiledFilter { exclude: RegexSet::new(&self.exclude)?, truncate_at: RegexSet::new(&self.truncate_at)? })
+    }
+}
+
+/// The regular expressions of a `FrameFilter`, ready to be matched.
+struct CompiledFilter {
+    exclude: RegexSet,
+    truncate_at: RegexSet,
+}
+
+impl CompiledFilter {
+    /// Applies the rules to a root-first stack.
+    fn apply(&self, stack: Vec<StackFrame>) -> Vec<StackFrame> {
+        if self.exclude.is_empty() && self.truncate_at.is_empty() {
+            return stack;
+        }
+        stack
+            .into_iter()
+            .take_while(|frame| !self.truncate_at.is_match(&frame.name))
+            .filter(|frame| !self.exclude.is_match(&frame.name))
+            .collect()
+    }
+}
+
+/// A frame prepared for insertion into the `StackTrie`.
+#[derive(Debug, Clone)]
+struct StackFrame {
+    name: String,
+    /// Selected local variables of the frame as `(name, value)` pairs.
+    locals: Vec<(String, String)>,
+    category: FrameCategory,
+}
+
+impl StackFrame {
+    fn from_frame(frame: &Frame, selected_locals: &[String]) -> Self {
+        match frame {
+            Frame::CFrame(frame) => StackFrame {
+                name: format!("{} ({}:{})", frame.func, frame.file, frame.lineno),
+                locals: Vec::new(),
+                category: classify_native_frame(frame),
+            },
+            Frame::PyFrame(frame) => StackFrame {
+                name: format!("{} ({}:{})", frame.func, frame.file, frame.lineno),
+                locals: select_locals(frame, selected_locals),
+                category: classify_python_file(&frame.file),
+            },
+            Frame::CoroFrame(frame) => StackFrame {
+                name: format!("{} ({}:{})", frame.func, frame.file, frame.lineno),
+                locals: Vec::new(),
+                category: classify_python_file(&frame.file),
+            },
+            Frame::Unknown(raw) => StackFrame {
+                name: unknown_frame_name(raw),
+                locals: Vec::new(),
+                category: FrameCategory::default(),
+            },
+        }
+    }
+}
+
+/// Python code from installed packages or the standard library is library code,
+/// everything else belongs to the job.
+fn classify_python_file(file: &str) -> FrameCategory {
+    if file.contains("/site-packages/") || file.contains("/dist-packages/") || file.contains("/lib/python3") {
+        FrameCategory::PythonLibrary
+    } else {
+        FrameCategory::PythonUser
+    }
+}
+
+/// Classifies a native frame by its function name and source file.
+fn classify_native_frame(frame: &CFrame) -> FrameCategory {
+    let func = frame.func.as_str();
+    let file = frame.file.as_str();
+    if func.starts_with("c10d::") || func.to_ascii_lowercase().contains("nccl") || file.contains("libnccl") {
+        FrameCategory::Nccl
+    } else if func.starts_with("cuda") || (func.starts_with("cu") && file.contains("libcuda")) || file.contains("libcudart") {
+        FrameCategory::Cuda
+    } else if ["at::", "c10::", "torch::", "pybind11::", "THP"].iter().any(|prefix| func.contains(prefix))
+        || file.contains("/torch/")
+    {
+        FrameCategory::Torch
+    } else if func.starts_with("Py") || func.starts_with("_Py") || func.starts_with("pymain")
+        || file.contains("/Python/") || file.contains("/Objects/") || file.contains("/Modules/")
+    {
+        FrameCategory::Interpreter
+    } else {
+        FrameCategory::Native
+    }
+}
+
+/// Picks the requested variables out of a `PyFrame.locals` object.
+fn select_locals(frame: &PyFrame, selected: &[String]) -> Vec<(String, String)> {
+    selected
+        .iter()
+        .filter_map(|name| frame.local(name).map(|value| (name.clone(), value)))
+        .collect()
+}
+
+/// Renders a local variable value for display.
+/// Single-key objects such as `{"Int": 1200}` are unwrapped to their inner value.
+fn local_value_to_string(value: &serde_json::Value) -> String {
+    match value {
+        serde_json::Value::String(s) => s.clone(),
+        serde_json::Value::Object(map) if map.len() == 1 => {
+            local_value_to_string(map.values().next().expect("map has one entry"))
+        }
+        other => other.to_string(),
+    }
+}
+
+/// Formats a sorted rank list as compact ranges, e.g. `[0, 1, 2, 5]` -> `0-2/5`.
+pub fn format_rank_ranges(ranks: &[u32]) -> String {
+    if ranks.is_empty() {
+        return String::new();
+    }
+
+    let mut ranges = Vec::new();
+    let mut i = 0;
+    let n = ranks.len();
+
+    while i < n {
+        let start = ranks[i];
+        let mut end = start;
+
+        // 与Python实现保持一致的区间合并逻辑
+        while i + 1 < n && ranks[i + 1] == end + 1 {
+            end = ranks[i + 1];
+            i += 1;
+        }
+
+        let range_str = if start == end {
+            start.to_string()
+        } else {
+            format!("{}-{}", start, end)
+        };
+
+        ranges.push(range_str);
+        i += 1;
+    }
+
+    ranges.join("/")
 }
 
 /// Represents a node in the Trie structure for stack traces.
@@ -43,6 +602,13 @@ pub struct TrieNode {
     children: HashMap<String, TrieNode>,
     is_end_of_stack: bool,
     ranks: BTreeSet<u32>, // 使用BTreeSet确保唯一性和有序性
+    /// Ranks with a stack ending at this node; a rank may also continue deeper with another thread.
+    ending: BTreeSet<u32>,
+    /// Selected local variables: variable name -> value -> ranks reporting that value.
+    locals: BTreeMap<String, BTreeMap<String, BTreeSet<u32>>>,
+    /// `(rank, worker)` of the worker processes passing through this node.
+    workers: BTreeSet<(u32, u32)>,
+    category: FrameCategory,
 }
 
 impl TrieNode {
@@ -51,12 +617,45 @@ impl TrieNode {
             children: HashMap::new(),
             is_end_of_stack: false,
             ranks: BTreeSet::new(),
+            ending: BTreeSet::new(),
+            locals: BTreeMap::new(),
+            workers: BTreeSet::new(),
+            category: FrameCategory::default(),
         }
     }
 
     fn add_rank(&mut self, rank: u32) {
         self.ranks.insert(rank); // 自动去重
     }
+
+    /// Returns the selected locals reported by one rank at this node.
+    fn locals_of_rank(&self, rank: u32) -> Vec<(String, String)> {
+        self.locals
+            .iter()
+            .filter_map(|(name, values)| {
+                values
+                    .iter()
+                    .find(|(_, ranks)| ranks.contains(&rank))
+                    .map(|(value, _)| (name.clone(), value.clone()))
+            })
+            .collect()
+    }
+
+    /// Ranks with a stack ending at this node rather than continuing into a deeper frame.
+    fn ending_ranks(&self) -> BTreeSet<u32> {
+        self.ending.clone()
+    }
+
+    fn add_locals(&mut self, locals: &[(String, String)], rank: u32) {
+        for (name, value) in locals {
+            self.locals
+                .entry(name.clone())
+                .or_default()
+                .entry(value.clone())
+                .or_default()
+                .insert(rank);
+        }
+    }
 }
 
 /// Represents a Trie structure for merging stack traces.
@@ -69,173 +668,321 @@ impl StackTrie {
     fn new(all_ranks: Vec<u32>) -> Self {
         // 将all_ranks转换为BTreeSet确保唯一性和有序性
         let all_ranks_set: BTreeSet<_> = all_ranks.into_iter().collect();
-        
+
         StackTrie {
             root: TrieNode::new(),
             all_ranks: all_ranks_set,
         }
     }
 
-    fn insert(&mut self, stack: Vec<&str>, rank: u32) {
+    #[cfg(test)]
+    fn insert(&mut self, stack: &[StackFrame], rank: u32) {
+        self.insert_process(stack, rank, None);
+    }
+
+    /// Inserts a stack of `rank`, or of one of its worker processes.
+    fn insert_process(&mut self, stack: &[StackFrame], rank: u32, worker: Option<u32>) {
         let mut node = &mut self.root;
         for frame in stack {
             // 跳过包含"lto_priv"的帧，与Python实现保持一致
-            if frame.contains("lto_priv") {
+            if frame.name.contains("lto_priv") {
                 break;
             }
-            
-            node = node.children.entry(frame.to_string()).or_insert_with(TrieNode::new);
+
+            node = node.children.entry(frame.name.clone()).or_insert_with(TrieNode::new);
             node.add_rank(rank);
+            node.add_locals(&frame.locals, rank);
+            node.workers.extend(worker.map(|worker| (rank, worker)));
+            node.category = frame.category;
         }
         node.is_end_of_stack = true;
+        node.ending.insert(rank);
         node.add_rank(rank); // 保留这行，与Python实现一致
     }
 
-    fn format_rank_str(&self, ranks: &BTreeSet<u32>) -> String {
-        // 转换为有序向量
-        let ranks_vec: Vec<_> = ranks.iter().cloned().collect();
-        
-        // 计算leak_ranks，使用集合操作确保正确性
-        let leak_ranks: Vec<_> = self.all_ranks
-            .difference(ranks)
-            .cloned()
-            .collect();
-
-        fn inner_format(ranks: &[u32]) -> String {
-            if ranks.is_empty() {
-                return String::new();
-            }
+    /// Builds the trie re-rooted at the leaf frames: every rank's stack is inserted leaf first,
+    /// so ranks blocked in the same place share a root regardless of how they got there.
+    fn inverted(&self) -> StackTrie {
+        let mut inverted = StackTrie {
+            root: TrieNode::new(),
+            all_ranks: self.all_ranks.clone(),
+        };
+        let mut path = Vec::new();
+        self.invert_into(&self.root, &mut path, &mut inverted);
+        inverted
+    }
 
-            let mut ranges = Vec::new();
-            let mut i = 0;
-            let n = ranks.len();
-            
-            while i < n {
-                let start = ranks[i];
-                let mut end = start;
-                
-                // 与Python实现保持一致的区间合并逻辑
-                while i + 1 < n && ranks[i + 1] == end + 1 {
-                    end = ranks[i + 1];
-                    i += 1;
+    fn invert_into<'a>(&'a self, node: &'a TrieNode, path: &mut Vec<(&'a str, &'a TrieNode)>, inverted: &mut StackTrie) {
+        for (frame, child) in &node.children {
+            path.push((frame, child));
+            if child.is_end_of_stack {
+                for rank in child.ending_ranks() {
+                    // 子进程的调用栈以其 [rank N / worker k] 层开头
+                    let worker = path
+                        .first()
+                        .and_then(|(_, top)| top.workers.iter().find(|(owner, _)| *owner == rank))
+                        .map(|(_, worker)| *worker);
+                    let stack: Vec<StackFrame> = path
+                        .iter()
+                        .rev()
+                        .map(|(name, node)| StackFrame {
+                            name: name.to_string(),
+                            locals: node.locals_of_rank(rank),
+                            category: node.category,
+                        })
+                        .collect();
+                    inverted.insert_process(&stack, rank, worker);
                 }
-                
-                let range_str = if start == end {
-                    start.to_string()
-                } else {
-                    format!("{}-{}", start, end)
-                };
-                
-                ranges.push(range_str);
-                i += 1;
             }
-            
-            ranges.join("/")
+            self.invert_into(child, path, inverted);
+            path.pop();
         }
-
-        let has_stack_ranks = inner_format(&ranks_vec);
-        let leak_stack_ranks = inner_format(&leak_ranks);
-        format!("@{}|{}", has_stack_ranks, leak_stack_ranks)
     }
 
-    pub fn traverse_with_all_stack<'a>(&'a self, node: &'a TrieNode, path: Vec<&str>) -> Vec<(Vec<String>, String)> {
+    /// Returns every merged stack as a list of frame names, root first, with the ranks whose stack ends there.
+    /// Frame names are kept clean; rank membership is carried by `collect_frame_meta`.
+    pub fn traverse_with_all_stack<'a>(&'a self, node: &'a TrieNode, path: Vec<&'a str>) -> Vec<(Vec<String>, BTreeSet<u32>)> {
         let mut result = Vec::new();
         for (frame, child) in &node.children {
-            let rank_str = self.format_rank_str(&child.ranks);
+            let mut child_path = path.clone();
+            child_path.push(frame);
             if child.is_end_of_stack {
-                let path_str = path.join(";");
-                result.push((vec![path_str, frame.to_string()], rank_str.clone()));
+                result.push((child_path.iter().map(|f| f.to_string()).collect(), child.ending_ranks()));
             }
-            let mut child_path = path.clone();
-            let frame_rank = format!("{}{}", frame, rank_str);
-            child_path.push(&frame_rank[..]);
             result.extend(self.traverse_with_all_stack(child, child_path));
         }
         result
     }
+
+    /// Collects the annotations of every frame name written by `traverse_with_all_stack`.
+    /// A function reached through several paths gets the union of their ranks and locals,
+    /// and is flagged when the paths are reached by different ranks.
+    fn collect_frame_meta(&self) -> FrameMeta {
+        let mut meta = FrameMeta {
+            captured_at: Some(Local::now().format("%Y-%m-%d %H:%M:%S").to_string()),
+            icicle: false,
+            all_ranks: self.all_ranks.clone(),
+            rank_nodes: BTreeMap::new(),
+            stack_ranks: Vec::new(),
+            processes: BTreeMap::new(),
+            frames: BTreeMap::new(),
+        };
+        let mut first_ranks: BTreeMap<&str, &BTreeSet<u32>> = BTreeMap::new();
+        let mut stack = vec![&self.root];
+        while let Some(node) = stack.pop() {
+            for (frame, child) in &node.children {
+                let info = meta.frames.entry(frame.clone()).or_default();
+                let first = first_ranks.entry(frame).or_insert(&child.ranks);
+                info.ranks_differ_by_path |= **first != child.ranks;
+                info.ranks.extend(&child.ranks);
+                info.merge_locals(&child.locals);
+                info.workers.extend(&child.workers);
+                info.category = Some(child.category);
+                stack.push(child);
+            }
+        }
+        meta
+    }
+}
+
+/// Call stacks of one collection, parsed and merged into a trie.
+pub struct MergedStacks {
+    /// Main thread stacks, leaf first; `frames[i]` belongs to `rank_list[i]`.
+    frames: Vec<Vec<Frame>>,
+    rank_list: Vec<u32>,
+    trie: StackTrie,
 }
 
-/// Process call stacks from a JSON string, merge them, and write the result to an output file.
-pub fn process_and_merge_callstacks(json_data: &str, rank_list: Vec<u32>, output_path: Option<&str>) -> Result<(), Box<dyn Error>> {
+/// Parses call stacks from a JSON string and merges them into a trie.
+/// `rank_list[i]` is the rank of the i-th stack.
+pub fn merge_callstacks(json_data: &str, rank_list: Vec<u32>, options: &MergeOptions) -> Result<MergedStacks, Box<dyn Error>> {
     // Parse the JSON data
-    let frames:  Vec<Vec<Frame>> = serde_json::from_str(json_data)?;
+    let dumps = parse_callstacks(json_data)?;
 
-    // Process the call stacks
-    let mut out_stacks = Vec::new();
-    for (_, trace) in frames.iter().enumerate() {
-        let mut local_stack = Vec::new();
-        for frame in trace {
-            local_stack.push(frame.clone());
+    // Ensure the number of stacks does not exceed the number of ranks
+    println!("prepare stacks length {}", dumps.len());
+    println!("rank list length {}", rank_list.len());
+    if dumps.len() > rank_list.len() {
+        return Err("Number of stacks exceeds number of ranks".into());
+    }
+    for (dump, rank) in dumps.iter().zip(&rank_list) {
+        for warning in dump.schema_warnings() {
+            eprintln!("Warning: rank {}: {}", rank, warning);
         }
-        local_stack.reverse();
-        out_stacks.push(local_stack);
     }
 
-    // Prepare stack strings
-    let mut prepare_stacks = Vec::new();
-    for rank in out_stacks {
-        if!rank.is_empty() {
-            let data = rank
-                .iter()
-                .map(|entry| match entry {
-                    Frame::CFrame(frame) => format!("{} ({}:{})", frame.func, frame.file, frame.lineno),
-                    Frame::PyFrame(frame) => format!("{} ({}:{})", frame.func, frame.file, frame.lineno),
-                })
-                .collect::<Vec<String>>()
-                .join(";");
-            prepare_stacks.push(data);
+    // Split every rank into its logical stacks: the levels naming the worker and thread, then its frames.
+    // 只要有一个rank返回了所有线程，单栈的rank也放在主线程层下
+    let threaded = dumps.iter().any(RankStacks::has_threads);
+    let mut frames = Vec::new();
+    let mut stack_ranks = Vec::new();
+    let mut stack_workers = Vec::new();
+    let mut stack_levels = Vec::new();
+    let mut stack_frames = Vec::new();
+    for (dump, &rank) in dumps.into_iter().zip(&rank_list) {
+        let single = dump.is_single();
+        let (threads, workers) = dump.into_parts();
+        let main = main_thread_index(&threads);
+        frames.push(threads.get(main).map(|thread| thread.frames.clone()).unwrap_or_default());
+
+        let mut processes = vec![(None, single, threads)];
+        for worker in workers {
+            let single = worker.stacks.is_single();
+            processes.push((Some(worker.worker), single, worker.stacks.into_threads()));
+        }
+        for (worker, single, threads) in processes {
+            let main = main_thread_index(&threads);
+            for (index, thread) in threads.into_iter().enumerate() {
+                let Some(mut levels) = options.threads.level(&thread, single, threaded, index == main) else {
+                    continue;
+                };
+                if let Some(worker) = worker {
+                    levels.insert(0, format!("[rank {} / worker {}]", rank, worker));
+                }
+                // 每个asyncio task是一条单独的调用栈，接在所属线程之后
+                for task in thread.tasks {
+                    let mut task_levels = levels.clone();
+                    task_levels.push(format!("[task {}]", task.label()));
+                    stack_ranks.push(rank);
+                    stack_workers.push(worker);
+                    stack_levels.push(task_levels);
+                    stack_frames.push(task.frames);
+                }
+                stack_ranks.push(rank);
+                stack_workers.push(worker);
+                stack_levels.push(levels);
+                stack_frames.push(thread.frames);
+            }
         }
     }
 
+    // Resolve file:line of native frames from local ELF files
+    if options.symbolize.is_enabled() {
+        let resolved = symbolize_frames(&mut stack_frames, &stack_ranks, &options.symbolize)?;
+        println!("Symbolized {} native frames", resolved);
+    }
+
     // Initialize StackTrie directly using the provided rank list
+    let filter = options.filter.compile().map_err(|e| format!("Invalid frame filter: {}", e))?;
     let mut trie = StackTrie::new(rank_list.clone());
+    for (((trace, levels), rank), worker) in stack_frames.iter().zip(stack_levels).zip(stack_ranks).zip(stack_workers) {
+        if trace.is_empty() {
+            continue;
+        }
+        let stack: Vec<StackFrame> = levels
+            .into_iter()
+            .map(|name| StackFrame { name, locals: Vec::new(), category: FrameCategory::default() })
+            .chain(trace.iter().rev().map(|frame| StackFrame::from_frame(frame, &options.locals)))
+            .collect();
+        trie.insert_process(&filter.apply(stack), rank, worker);
+    }
 
-    // Ensure the number of stacks does not exceed the number of ranks
-    println!("prepare stacks length {}", prepare_stacks.len());
-    println!("rank list length {}", rank_list.len());
-    if prepare_stacks.len() > rank_list.len() {
-        return Err("Number of stacks exceeds number of ranks".into());
+    // Re-root at the leaf frames for the icicle view
+    if options.icicle {
+        trie = trie.inverted();
+    }
+
+    Ok(MergedStacks { frames, rank_list, trie })
+}
+
+impl MergedStacks {
+    /// Collects the annotations of every merged frame, including the ranks of each merged stack.
+    pub fn frame_meta(&self, options: &MergeOptions) -> FrameMeta {
+        let mut meta = self.trie.collect_frame_meta();
+        if let Some(captured_at) = &options.captured_at {
+            meta.captured_at = Some(captured_at.clone());
+        }
+        meta.icicle = options.icicle;
+        meta.rank_nodes = options.rank_nodes.clone();
+        meta.processes = options.processes.clone();
+        meta.stack_ranks = self
+            .trie
+            .traverse_with_all_stack(&self.trie.root, Vec::new())
+            .into_iter()
+            .map(|(_, ranks)| ranks)
+            .collect();
+        meta
     }
 
-    for (index, stack) in prepare_stacks.iter().enumerate() {
-        let stack_frames: Vec<&str> = stack.split(';').collect();
-        // Use the rank value at the corresponding index in the rank list
-        let rank = rank_list[index];
-        trie.insert(stack_frames, rank);
-    }
-
-    // Determine the output file path
-    let output_path = match output_path {
-        // Use the specified output path if provided
-        Some(path) => {
-            let output_dir = PathBuf::from(path);
-            // Create the output directory if it doesn't exist
-            std::fs::create_dir_all(&output_dir)?;
-            let timestamp = Local::now().format("%Y%m%d%H%M%S").to_string();
-            output_dir.join(format!("stacktrace_{}.txt", timestamp))
-        }
-        // Use the default output path in /tmp/output_xxxx/merged_stack
-        None => {
-            let date = Local::now().format("%Y%m%d").to_string();
-            let output_dir = PathBuf::from("/tmp").join(format!("output_{}", date)).join("merged_stack");
-            // Create the output directory if it doesn't exist
-            std::fs::create_dir_all(&output_dir)?;
-            let timestamp = Local::now().format("%Y%m%d%H%M%S").to_string();
-            output_dir.join(format!("stacktrace_{}.txt", timestamp))
+    /// Writes the merged stacks in folded format, one `frame;frame;... 1` line per distinct stack.
+    pub fn write_folded<W: Write>(&self, writer: &mut W) -> std::io::Result<()> {
+        for (path, _) in self.trie.traverse_with_all_stack(&self.trie.root, Vec::new()) {
+            writeln!(writer, "{} 1", path.join(";"))?;
         }
-    };
+        Ok(())
+    }
+
+    /// Lists the groups of ranks sharing a merged stack, largest group first, by the frame they end in.
+    pub fn write_stack_groups<W: Write>(&self, writer: &mut W) -> std::io::Result<()> {
+        let mut groups = self.trie.traverse_with_all_stack(&self.trie.root, Vec::new());
+        groups.sort_by(|a, b| b.1.len().cmp(&a.1.len()).then_with(|| a.1.cmp(&b.1)));
+        writeln!(writer, "# {} distinct stacks from {} ranks", groups.len(), self.rank_list.len())?;
+        for (path, ranks) in groups {
+            let ranks: Vec<u32> = ranks.into_iter().collect();
+            let label = if ranks.len() == 1 { "rank" } else { "ranks" };
+            let frame = path.last().map(String::as_str).unwrap_or_default();
+            writeln!(writer, "{} {}: {}", label, format_rank_ranges(&ranks), frame)?;
+        }
+        Ok(())
+    }
+
+    /// Builds the process table, plus the step skew and selected locals reports requested by `options`.
+    /// Returns `None` when there is neither process metadata nor `step_var` or `locals`.
+    pub fn report(&self, meta: &FrameMeta, options: &MergeOptions) -> Result<Option<String>, Box<dyn Error>> {
+        if options.locals.is_empty() && options.step_var.is_none() && meta.processes.is_empty() {
+            return Ok(None);
+        }
+        let mut report = Vec::new();
+        if !meta.processes.is_empty() {
+            meta.write_processes_report(&mut report)?;
+        }
+        if let Some(var) = &options.step_var {
+            let skew = detect_step_skew(&self.frames, &self.rank_list, var);
+            writeln!(report, "{}", skew)?;
+        }
+        if !options.locals.is_empty() {
+            meta.write_locals_report(&mut report)?;
+        }
+        Ok(Some(String::from_utf8(report)?))
+    }
+}
+
+/// Process call stacks from a JSON string, merge them, and write the result to
+/// `stacktrace_<timestamp>.txt` in `output_dir`, next to its frame annotations and reports.
+/// Returns the path of the merged stack file.
+pub fn process_and_merge_callstacks(
+    json_data: &str,
+    rank_list: Vec<u32>,
+    output_dir: &Path,
+    options: &MergeOptions,
+) -> Result<PathBuf, Box<dyn Error>> {
+    let merged = merge_callstacks(json_data, rank_list, options)?;
+
+    // Create the output directory if it doesn't exist
+    std::fs::create_dir_all(output_dir)?;
+    let timestamp = Local::now().format("%Y%m%d%H%M%S").to_string();
+    let output_path = output_dir.join(format!("stacktrace_{}.txt", timestamp));
 
     // Create the output file
     let mut output_file = File::create(&output_path)?;
+    merged.write_folded(&mut output_file)?;
 
-    for (path, rank_str) in trie.traverse_with_all_stack(&trie.root, Vec::new()) {
-        writeln!(output_file, "{} {} 1", path.join(";"), rank_str)?;
-    }
+    // Write the frame annotations next to the merged stack so the flamegraph can show them
+    let meta = merged.frame_meta(options);
+    let meta_path = FrameMeta::sidecar_path(&output_path);
+    meta.save(&meta_path)?;
 
     // Print the output file path
     println!("Output file path: {}", output_path.display());
 
-    Ok(())
+    if let Some(report) = merged.report(&meta, options)? {
+        let report_path = output_path.with_extension("report.txt");
+        std::fs::write(&report_path, &report)?;
+        print!("{}", report);
+        println!("Report path: {}", report_path.display());
+    }
+
+    Ok(output_path)
 }
 
 #[cfg(test)]
@@ -244,6 +991,16 @@ mod tests {
     use std::fs;
     use std::path::Path;
 
+    /// A merged stack frame named `name`, without locals.
+    fn stack_frame(name: &str) -> StackFrame {
+        StackFrame { name: name.to_string(), locals: Vec::new(), category: FrameCategory::default() }
+    }
+
+    /// A raw Python frame of `func` in train.py.
+    fn py_frame(func: &str) -> serde_json::Value {
+        serde_json::json!({"PyFrame": {"file": "train.py", "func": func, "lineno": 1, "locals": {}}})
+    }
+
     /// Test the `process_and_merge_callstacks` function.
     #[test]
     fn test_process_and_merge_callstacks() {
@@ -253,21 +1010,303 @@ mod tests {
         assert!(Path::new(input_file_path).exists(), "Input file does not exist");
 
         // Define the output directory
-        let output_dir = "test";
+        let output_dir = std::env::temp_dir().join(format!("flame_test_process_{}", std::process::id()));
         // Define the rank list
-        let rank_list = vec![0, 1, 2]; 
+        let rank_list = vec![0, 1, 2, 3];
         // Call the function to process and merge call stacks
         let json_data = fs::read_to_string(input_file_path).expect("Failed to read input file");
-        process_and_merge_callstacks(&json_data, rank_list, Some(output_dir)).expect("Processing failed");
+        let expected_output_path = process_and_merge_callstacks(&json_data, rank_list, &output_dir, &MergeOptions::default())
+            .expect("Processing failed");
 
         // Verify if the output file exists
-        let input_path = Path::new(input_file_path);
-        let file_stem = input_path.file_stem().and_then(std::ffi::OsStr::to_str).unwrap();
-        let expected_output_path = Path::new(output_dir).join(format!("{}.txt", file_stem));
         assert!(expected_output_path.exists(), "Output file should be created");
 
         // Verify that the output file content is not empty
         let output_content = fs::read_to_string(&expected_output_path).expect("Failed to read output file");
         assert!(!output_content.is_empty(), "Output file should not be empty");
     }
-}
\ No newline at end of file
+
+    /// Every frame of the merged stack file carries its rank annotations in the sidecar instead of its name.
+    #[test]
+    fn test_merged_frames_are_annotated() {
+        let json_data = fs::read_to_string("test/merged_output.json").expect("Failed to read input file");
+        let output_dir = std::env::temp_dir().join(format!("flame_test_annotations_{}", std::process::id()));
+        let output_file = process_and_merge_callstacks(&json_data, vec![0, 1, 2, 3], &output_dir, &MergeOptions::default())
+            .expect("Processing failed");
+
+        let output_content = fs::read_to_string(&output_file).expect("Failed to read output file");
+        let meta = FrameMeta::load(&FrameMeta::sidecar_path(&output_file)).expect("Failed to load frame annotations");
+        assert_eq!(meta.all_ranks, BTreeSet::from([0, 1, 2, 3]));
+        for line in output_content.lines() {
+            let stack = line.rsplit_once(' ').expect("Missing sample count").0;
+            for frame in stack.split(';') {
+                let info = meta.frames.get(frame).expect("Frame should be annotated");
+                assert!(!info.ranks.is_empty(), "Frame '{}' should have ranks", frame);
+            }
+        }
+    }
+
+    /// Selected locals are summarized per frame with the ranks reporting each value.
+    #[test]
+    fn test_locals_are_summarized_per_frame() {
+        let json_data = fs::read_to_string("test/pyframe_locals.json").expect("Failed to read input file");
+        let frames: Vec<Vec<Frame>> = parse_callstacks(&json_data)
+            .expect("Invalid test data")
+            .into_iter()
+            .map(|stacks| stacks.into_threads().remove(0).frames)
+            .collect();
+        let selected = vec!["step".to_string()];
+
+        let mut trie = StackTrie::new(vec![0, 1, 2, 3]);
+        for (rank, trace) in frames.iter().enumerate() {
+            let stack: Vec<StackFrame> = trace
+                .iter()
+                .rev()
+                .map(|frame| StackFrame::from_frame(frame, &selected))
+                .collect();
+            trie.insert(&stack, rank as u32);
+        }
+
+        let meta = trie.collect_frame_meta();
+        let summaries: Vec<String> = meta
+            .frames
+            .iter()
+            .filter(|(name, _)| name.starts_with("train_step"))
+            .map(|(_, info)| info.locals_summary())
+            .collect();
+        assert_eq!(summaries, vec!["step=1200 on ranks 0-2, step=1199 on rank 3"]);
+    }
+
+    /// Threads of every rank merge under their role, or only the main threads are kept.
+    #[test]
+    fn test_threads_merge_by_role() {
+        let json_data = serde_json::json!([
+            {"threads": [
+                {"id": 11, "name": "MainThread", "frames": [py_frame("all_reduce"), py_frame("main")]},
+                {"id": 12, "name": "ProcessGroupNCCL watchdog", "frames": [py_frame("wait")]},
+            ]},
+            [
+                {"tid": 21, "thread_name": "pt_pin_memory", "frames": [py_frame("pin")]},
+                {"tid": 22, "thread_name": "worker", "is_main": true, "frames": [py_frame("backward"), py_frame("main")]},
+            ],
+            [py_frame("load_batch"), py_frame("main")],
+        ])
+        .to_string();
+        let children = |threads| {
+            let options = MergeOptions { threads, ..Default::default() };
+            let merged = merge_callstacks(&json_data, vec![0, 1, 2], &options).expect("Merge failed");
+            let mut children: Vec<_> = merged.trie.root.children.iter().map(|(name, node)| (name.clone(), node.ranks.clone())).collect();
+            children.sort();
+            (children, merged.frames.iter().map(Vec::len).collect::<Vec<_>>())
+        };
+
+        let (by_role, main_lengths) = children(ThreadMode::Role);
+        assert_eq!(
+            by_role,
+            vec![
+                ("[thread dataloader]".to_string(), BTreeSet::from([1])),
+                ("[thread main]".to_string(), BTreeSet::from([0, 1, 2])),
+                ("[thread nccl-watchdog]".to_string(), BTreeSet::from([0])),
+            ]
+        );
+        assert_eq!(main_lengths, vec![2, 2, 2]);
+
+        let (main_only, _) = children(ThreadMode::Main);
+        assert_eq!(main_only, vec![("main (train.py:1)".to_string(), BTreeSet::from([0, 1, 2]))]);
+    }
+
+    /// Worker stacks attached to their rank merge under `[rank N / worker k]` and are named in the annotations,
+    /// also once re-rooted for the icicle view.
+    #[test]
+    fn test_worker_stacks_are_tagged() {
+        let stacks = serde_json::json!([
+            [py_frame("all_reduce"), py_frame("main")],
+            attach_workers(
+                serde_json::json!([py_frame("all_reduce"), py_frame("main")]),
+                vec![(0, serde_json::json!([py_frame("read"), py_frame("_worker_loop")])), (2, serde_json::json!([py_frame("_worker_loop")]))],
+            ),
+        ])
+        .to_string();
+
+        for icicle in [false, true] {
+            let options = MergeOptions { icicle, ..Default::default() };
+            let merged = merge_callstacks(&stacks, vec![0, 1], &options).expect("Merge failed");
+            assert_eq!(merged.frames.iter().map(Vec::len).collect::<Vec<_>>(), vec![2, 2]);
+            let meta = merged.frame_meta(&options);
+            let main = &meta.frames["main (train.py:1)"];
+            assert_eq!((main.ranks.clone(), main.workers.clone()), (BTreeSet::from([0, 1]), BTreeSet::new()));
+            let worker_loop = &meta.frames["_worker_loop (train.py:1)"];
+            assert_eq!(worker_loop.ranks, BTreeSet::from([1]));
+            assert_eq!(worker_loop.workers_summary(), "rank 1 / worker 0, rank 1 / worker 2");
+            assert_eq!(meta.frames["[rank 1 / worker 2]"].workers, BTreeSet::from([(1, 2)]));
+            assert!(!meta.frames.keys().any(|name| name.starts_with("[thread")));
+        }
+
+        // 只有一个未命名线程的调用栈不当作单栈
+        let lone_thread = serde_json::json!({"threads": [{"frames": [py_frame("main")]}]});
+        let wrapped = attach_workers(serde_json::json!([py_frame("main")]), vec![(0, serde_json::json!([py_frame("main")]))]);
+        let dumps = parse_callstacks(&serde_json::json!([lone_thread, wrapped]).to_string()).expect("The array should parse");
+        assert_eq!(dumps.iter().map(RankStacks::is_single).collect::<Vec<_>>(), vec![false, true]);
+    }
+
+    /// Each asyncio task becomes its own stack under its thread, merging numbered tasks by coroutine.
+    #[test]
+    fn test_asyncio_tasks_merge_by_name() {
+        let coro = |func: &str| serde_json::json!({"CoroFrame": {"file": "serve.py", "func": func, "lineno": 7}});
+        let rank = |task_number: u32| {
+            serde_json::json!([{
+                "name": "MainThread",
+                "frames": [{"PyFrame": {"file": "/usr/lib/python3.11/selectors.py", "func": "select", "lineno": 468, "locals": {}}}],
+                "tasks": [
+                    {"name": format!("Task-{}", task_number), "coro": "handle_request", "frames": [coro("generate"), coro("handle_request")]},
+                    {"name": "weight-sync", "frames": [coro("recv"), coro("sync_weights")]},
+                ],
+            }])
+        };
+        let json_data = serde_json::json!([rank(12), rank(4031)]).to_string();
+
+        let merged = merge_callstacks(&json_data, vec![0, 1], &MergeOptions::default()).expect("Merge failed");
+        let mut paths: Vec<_> = merged.trie.traverse_with_all_stack(&merged.trie.root, Vec::new()).into_iter().map(|(path, _)| path.join(";")).collect();
+        paths.sort();
+        assert_eq!(
+            paths,
+            vec![
+                "[thread main];[task handle_request];handle_request (serve.py:7);generate (serve.py:7)",
+                "[thread main];[task weight-sync];sync_weights (serve.py:7);recv (serve.py:7)",
+                "[thread main];select (/usr/lib/python3.11/selectors.py:468)",
+            ]
+        );
+        let meta = merged.frame_meta(&MergeOptions::default());
+        assert_eq!(meta.frames["generate (serve.py:7)"].ranks, BTreeSet::from([0, 1]));
+        assert_eq!(meta.frames["generate (serve.py:7)"].category, Some(FrameCategory::PythonUser));
+    }
+
+    /// Unknown frame kinds, extra fields and `null` values do not fail the merge; an unreadable rank is left out.
+    #[test]
+    fn test_tolerant_frame_parsing() {
+        let json_data = r#"[
+            [
+                {"TraceFrame": {"span": 3}},
+                {"GpuFrame": {"func": "ncclDevKernel_AllReduce", "file": "all_reduce.cu", "lineno": 40, "stream": 7}},
+                {"CFrame": {"func": "cudaStreamSynchronize", "file": null, "ip": "0x7f", "lineno": null}},
+                {"PyFrame": {"func": "main", "file": "train.py", "lineno": 3, "locals": {}, "qualname": "main"}}
+            ],
+            {"threads": [{"main": true, "frames": [{"PyFrame": {"func": "main", "file": "train.py", "lineno": 3}}]}]},
+            "callstack unavailable: interpreter is shutting down"
+        ]"#;
+        let dumps = parse_callstacks(json_data).expect("The array should parse");
+        assert_eq!(
+            dumps[0].schema_warnings(),
+            vec![
+                "1 'GpuFrame' frames of an unknown kind or layout merged as generic frames",
+                "1 'TraceFrame' frames of an unknown kind or layout merged as generic frames",
+            ]
+        );
+        assert!(dumps[1].schema_warnings().is_empty());
+        assert!(dumps[2].schema_warnings()[0].starts_with("unreadable call stacks"));
+
+        let merged = merge_callstacks(json_data, vec![0, 1, 2], &MergeOptions::default()).expect("Merge failed");
+        let mut paths: Vec<_> = merged
+            .trie
+            .traverse_with_all_stack(&merged.trie.root, Vec::new())
+            .into_iter()
+            .map(|(path, ranks)| (path.join(";"), ranks))
+            .collect();
+        paths.sort();
+        assert_eq!(
+            paths,
+            vec![
+                ("[thread main];main (train.py:3)".to_string(), BTreeSet::from([1])),
+                (
+                    "[thread main];main (train.py:3);cudaStreamSynchronize (:0);ncclDevKernel_AllReduce (all_reduce.cu:40);[TraceFrame]"
+                        .to_string(),
+                    BTreeSet::from([0])
+                ),
+            ]
+        );
+        assert_eq!(merged.trie.all_ranks, BTreeSet::from([0, 1, 2]));
+    }
+
+    /// Frames are categorized from their variant plus function and file patterns.
+    #[test]
+    fn test_frame_categories() {
+        let native = |func: &str, file: &str| {
+            classify_native_frame(&CFrame {
+                file: file.to_string(),
+                func: func.to_string(),
+                ip: "0x0".to_string(),
+                lineno: 0,
+            })
+        };
+        assert_eq!(native("cudaStreamSynchronize", ""), FrameCategory::Cuda);
+        assert_eq!(native("c10d::ProcessGroupNCCL::WorkNCCL::wait(...)", ""), FrameCategory::Nccl);
+        assert_eq!(native("at::native::add_out(...)", ""), FrameCategory::Torch);
+        assert_eq!(native("_PyEval_EvalFrameDefault", "/usr/local/src/conda/python-3.11.10/Python/ceval.c"), FrameCategory::Interpreter);
+        assert_eq!(native("__libc_start_main_impl", "./csu/../csu/libc-start.c"), FrameCategory::Native);
+
+        assert_eq!(classify_python_file("/workspace/train.py"), FrameCategory::PythonUser);
+        assert_eq!(
+            classify_python_file("/opt/conda/lib/python3.11/site-packages/torch/nn/modules/module.py"),
+            FrameCategory::PythonLibrary
+        );
+    }
+
+    /// A frame reached through paths with different ranks is flagged instead of listing its missing ranks.
+    #[test]
+    fn test_frame_ranks_differ_by_path() {
+        let mut trie = StackTrie::new(vec![0, 1, 2]);
+        trie.insert(&[stack_frame("main"), stack_frame("train"), stack_frame("all_reduce")], 0);
+        trie.insert(&[stack_frame("main"), stack_frame("eval"), stack_frame("all_reduce")], 1);
+        trie.insert(&[stack_frame("main"), stack_frame("train"), stack_frame("load_batch")], 2);
+
+        let meta = trie.collect_frame_meta();
+        let summary = |name: &str| meta.frames[name].ranks_summary(&meta.all_ranks);
+        assert_eq!(summary("all_reduce"), "ranks differ by path, 0-1 over all paths");
+        assert_eq!(summary("train"), "ranks 0/2 | missing 1");
+        assert!(!meta.frames["main"].ranks_differ_by_path);
+    }
+
+    /// The icicle trie groups ranks by the leaf frame they are blocked in.
+    #[test]
+    fn test_inverted_trie_groups_ranks_by_leaf() {
+        let mut trie = StackTrie::new(vec![0, 1, 2]);
+        trie.insert(&[stack_frame("main"), stack_frame("train"), stack_frame("all_reduce")], 0);
+        trie.insert(&[stack_frame("main"), stack_frame("eval"), stack_frame("all_reduce")], 1);
+        trie.insert(&[stack_frame("main"), stack_frame("train"), stack_frame("load_batch")], 2);
+
+        let inverted = trie.inverted();
+        let mut leaves: Vec<_> = inverted
+            .root
+            .children
+            .iter()
+            .map(|(name, node)| (name.clone(), node.ranks.clone()))
+            .collect();
+        leaves.sort();
+        assert_eq!(
+            leaves,
+            vec![
+                ("all_reduce".to_string(), BTreeSet::from([0, 1])),
+                ("load_batch".to_string(), BTreeSet::from([2])),
+            ]
+        );
+        let mut paths = inverted.traverse_with_all_stack(&inverted.root, Vec::new());
+        paths.sort();
+        assert_eq!(paths[0], (vec!["all_reduce".to_string(), "eval".to_string(), "main".to_string()], BTreeSet::from([1])));
+    }
+
+    /// Excluded frames are dropped and stacks are cut at the first truncating frame.
+    #[test]
+    fn test_frame_filter() {
+        let filter = FrameFilter {
+            exclude: vec!["^_PyEval_".to_string()],
+            truncate_at: vec!["^ncclKernel".to_string()],
+        };
+        let stack = vec![stack_frame("main"), stack_frame("_PyEval_EvalFrameDefault"), stack_frame("all_reduce"), stack_frame("ncclKernel_AllReduce"), stack_frame("deep")];
+
+        let names: Vec<String> = filter.compile().expect("Valid filter").apply(stack).into_iter().map(|f| f.name).collect();
+        assert_eq!(names, vec!["main", "all_reduce"]);
+
+        let invalid = FrameFilter { exclude: vec!["(".to_string()], ..Default::default() };
+        assert!(invalid.compile().is_err());
+    }
+}
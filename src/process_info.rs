@@ -0,0 +1,202 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+/// Path of the Probing endpoint describing the training process.
+pub const OVERVIEW_PATH: &str = "/apis/overview";
+
+/// Identity of the training process behind a rank, recorded next to its call stack.
+#[derive(Debug, Default, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct ProcessInfo {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hostname: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pid: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cmdline: Option<String>,
+    /// `RANK` from the environment of the process.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rank: Option<u32>,
+    /// `LOCAL_RANK` from the environment of the process.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub local_rank: Option<u32>,
+    /// `WORLD_SIZE` from the environment of the process.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub world_size: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub python_version: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub probing_version: Option<String>,
+}
+
+/// Reads the environment of an overview, given as an object or as `KEY=VALUE` lines
+/// separated by newlines or NULs as in `/proc/<pid>/environ`.
+fn overview_env(overview: &Value) -> BTreeMap<String, String> {
+    match overview.get("env").or_else(|| overview.get("environ")) {
+        Some(Value::Object(map)) => map
+            .iter()
+            .filter_map(|(key, value)| Some((key.clone(), value.as_str()?.to_string())))
+            .collect(),
+        Some(Value::String(env)) => env
+            .split(['\n', '\0'])
+            .filter_map(|line| line.split_once('='))
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect(),
+        _ => BTreeMap::new(),
+    }
+}
+
+/// The first string among `keys`, also accepting an array of strings, joined by spaces.
+fn string_field(overview: &Value, keys: &[&str]) -> Option<String> {
+    keys.iter().find_map(|key| match overview.get(key)? {
+        Value::String(value) if !value.is_empty() => Some(value.replace('\0', " ").trim().to_string()),
+        Value::Array(parts) => Some(parts.iter().filter_map(Value::as_str).collect::<Vec<_>>().join(" ")),
+        Value::Number(number) => Some(number.to_string()),
+        _ => None,
+    })
+}
+
+impl ProcessInfo {
+    /// Extracts the process identity from a Probing overview response. Missing fields stay unset,
+    /// so responses of older or newer Probing versions still give what they can.
+    pub fn from_overview(overview: &Value) -> Self {
+        let env = overview_env(overview);
+        let env_number = |name: &str| env.get(name).and_then(|value| value.trim().parse().ok());
+        ProcessInfo {
+            hostname: string_field(overview, &["hostname", "host"]).or_else(|| env.get("HOSTNAME").cloned()),
+            pid: overview.get("pid").and_then(Value::as_u64).and_then(|pid| u32::try_from(pid).ok()),
+            cmdline: string_field(overview, &["cmdline", "cmd"]),
+            rank: env_number("RANK"),
+            local_rank: env_number("LOCAL_RANK"),
+            world_size: env_number("WORLD_SIZE"),
+            python_version: string_field(overview, &["python_version", "python"]),
+            probing_version: string_field(overview, &["probing_version", "version"]),
+        }
+    }
+
+    /// One-line description, e.g. `gpu002 pid 4242`.
+    pub fn summary(&self) -> String {
+        let mut parts = Vec::new();
+        if let Some(hostname) = &self.hostname {
+            parts.push(hostname.clone());
+        }
+        if let Some(pid) = self.pid {
+            parts.push(format!("pid {}", pid));
+        }
+        if parts.is_empty() {
+            "unknown process".to_string()
+        } else {
+            parts.join(" ")
+        }
+    }
+}
+
+/// Path of the process metadata saved next to a raw call stack file,
+/// e.g. `callstacks_<ts>.processes.json` for `callstacks_<ts>.raw.json`.
+pub fn processes_path(raw_file: &Path) -> PathBuf {
+    let name = raw_file.file_name().map(|name| name.to_string_lossy().to_string()).unwrap_or_default();
+    let stem = name.strip_suffix(".raw.json").or_else(|| name.strip_suffix(".json")).unwrap_or(&name);
+    raw_file.with_file_name(format!("{}.processes.json", stem))
+}
+
+/// Result of comparing the configured rank of each stack with the `RANK` its process reports.
+#[derive(Debug, Default, PartialEq)]
+pub struct IdentityCheck {
+    /// `(configured, reported)` ranks that differ.
+    pub mismatches: Vec<(u32, u32)>,
+    /// Reported ranks claimed by several configured ranks.
+    pub duplicates: BTreeMap<u32, Vec<u32>>,
+    /// Configured ranks whose process did not report a rank.
+    pub unverified: Vec<u32>,
+}
+
+impl IdentityCheck {
+    /// Compares `rank_list` with the ranks reported in `processes`, keyed by configured rank.
+    pub fn new(rank_list: &[u32], processes: &BTreeMap<u32, ProcessInfo>) -> Self {
+        let mut check = IdentityCheck::default();
+        let mut claimed: BTreeMap<u32, Vec<u32>> = BTreeMap::new();
+        for rank in rank_list {
+            match processes.get(rank).and_then(|info| info.rank) {
+                Some(reported) => {
+                    if reported != *rank {
+                        check.mismatches.push((*rank, reported));
+                    }
+                    claimed.entry(reported).or_default().push(*rank);
+                }
+                None => check.unverified.push(*rank),
+            }
+        }
+        check.duplicates = claimed.into_iter().filter(|(_, ranks)| ranks.len() > 1).collect();
+        check
+    }
+
+    /// Whether every verified stack comes from the process of its configured rank.
+    pub fn is_consistent(&self) -> bool {
+        self.mismatches.is_empty() && self.duplicates.is_empty()
+    }
+}
+
+impl std::fmt::Display for IdentityCheck {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (configured, reported) in &self.mismatches {
+            writeln!(f, "rank {}: the process reports RANK={}", configured, reported)?;
+        }
+        for (reported, ranks) in &self.duplicates {
+            let ranks: Vec<String> = ranks.iter().map(u32::to_string).collect();
+            writeln!(f, "ranks {} all report RANK={}", ranks.join(", "), reported)?;
+        }
+        if !self.unverified.is_empty() {
+            writeln!(f, "{} ranks could not be verified, their processes did not report RANK", self.unverified.len())?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Overviews with the environment as a string or an object both yield the identity.
+    #[test]
+    fn test_process_info_from_overview() {
+        let overview = serde_json::json!({
+            "pid": 4242,
+            "cmd": "/opt/conda/bin/python\u{0}train.py\u{0}--lr=0.1",
+            "env": "HOSTNAME=gpu002\nRANK=7\nLOCAL_RANK=3\nWORLD_SIZE=8",
+            "version": "0.2.1",
+        });
+        let info = ProcessInfo::from_overview(&overview);
+        assert_eq!(info.hostname.as_deref(), Some("gpu002"));
+        assert_eq!(info.pid, Some(4242));
+        assert_eq!(info.cmdline.as_deref(), Some("/opt/conda/bin/python train.py --lr=0.1"));
+        assert_eq!((info.rank, info.local_rank, info.world_size), (Some(7), Some(3), Some(8)));
+        assert_eq!(info.probing_version.as_deref(), Some("0.2.1"));
+        assert_eq!(info.summary(), "gpu002 pid 4242");
+
+        let info = ProcessInfo::from_overview(&serde_json::json!({"env": {"RANK": "2"}, "hostname": "gpu001"}));
+        assert_eq!((info.hostname.as_deref(), info.rank, info.pid), (Some("gpu001"), Some(2), None));
+
+        assert_eq!(
+            processes_path(Path::new("/runs/callstacks_1.raw.json")),
+            PathBuf::from("/runs/callstacks_1.processes.json")
+        );
+    }
+
+    /// A stale address pointing at another rank's process is caught, unknown identities are only counted.
+    #[test]
+    fn test_identity_check() {
+        let reporting = |rank| ProcessInfo { rank: Some(rank), ..Default::default() };
+        let processes = BTreeMap::from([(0, reporting(0)), (1, reporting(1)), (5, reporting(1)), (6, ProcessInfo::default())]);
+
+        let check = IdentityCheck::new(&[0, 1, 5, 6], &processes);
+        assert!(!check.is_consistent());
+        assert_eq!(check.mismatches, vec![(5, 1)]);
+        assert_eq!(check.duplicates, BTreeMap::from([(1, vec![1, 5])]));
+        assert_eq!(check.unverified, vec![6]);
+        assert!(check.to_string().contains("rank 5: the process reports RANK=1"));
+
+        assert!(IdentityCheck::new(&[0, 1, 6], &processes).is_consistent());
+    }
+}
@@ -0,0 +1,169 @@
+use chrono::Local;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// File name of the manifest written into every run directory.
+pub const MANIFEST: &str = "manifest.json";
+
+/// A file written by a run, relative to the run directory.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct Artifact {
+    /// What the file holds, e.g. `merged_stacks` or `flamegraph`.
+    pub kind: String,
+    pub path: PathBuf,
+}
+
+/// Record of one invocation: what it read, with which settings, and what it wrote.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct Manifest {
+    pub tool_version: String,
+    /// Command line of the invocation.
+    pub command: Vec<String>,
+    pub started_at: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub finished_at: Option<String>,
+    /// Input files by role, e.g. `url_file` or `stack_file`.
+    #[serde(default)]
+    pub inputs: BTreeMap<String, PathBuf>,
+    /// Ranks the call stacks were collected from, in collection order.
+    #[serde(default)]
+    pub ranks: Vec<u32>,
+    /// Call stack URL of each rank.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub targets: BTreeMap<u32, String>,
+    /// Effective settings by group, e.g. `merge` or `draw`.
+    #[serde(default)]
+    pub settings: BTreeMap<String, serde_json::Value>,
+    #[serde(default)]
+    pub artifacts: Vec<Artifact>,
+}
+
+impl Manifest {
+    /// Records a group of settings.
+    pub fn set_settings<T: Serialize>(&mut self, group: &str, settings: &T) -> Result<(), serde_json::Error> {
+        self.settings.insert(group.to_string(), serde_json::to_value(settings)?);
+        Ok(())
+    }
+}
+
+/// Kind of an artifact, decided by the suffix its writer gave it.
+fn artifact_kind(file_name: &str) -> &'static str {
+    if file_name.ends_with(".raw.json") {
+        "raw_callstacks"
+    } else if file_name.ends_with(".processes.json") {
+        "process_info"
+    } else if file_name.ends_with(".meta.json") {
+        "frame_annotations"
+    } else if file_name.ends_with(".report.txt") {
+        "report"
+    } else if file_name.ends_with(".txt") {
+        "merged_stacks"
+    } else if file_name.ends_with(".svg") {
+        "flamegraph"
+    } else if file_name.ends_with(".html") {
+        "flamegraph_index"
+    } else {
+        "other"
+    }
+}
+
+/// Directory holding everything one invocation writes, plus its `manifest.json`.
+pub struct RunDir {
+    path: PathBuf,
+    pub manifest: Manifest,
+}
+
+impl RunDir {
+    /// Default base directory of the run directories.
+    pub fn default_base() -> PathBuf {
+        std::env::temp_dir().join("flame")
+    }
+
+    /// Creates a fresh `run_<timestamp>` directory under `base`.
+    pub fn create(base: &Path) -> Result<RunDir, Box<dyn Error>> {
+        let now = Local::now();
+        let name = format!("run_{}", now.format("%Y%m%d_%H%M%S"));
+        let mut path = base.join(&name);
+        let mut suffix = 1;
+        while path.exists() {
+            path = base.join(format!("{}_{}", name, suffix));
+            suffix += 1;
+        }
+        fs::create_dir_all(&path).map_err(|e| format!("Failed to create run directory {}: {}", path.display(), e))?;
+
+        let manifest = Manifest {
+            tool_version: env!("CARGO_PKG_VERSION").to_string(),
+            command: std::env::args().collect(),
+            started_at: now.format("%Y-%m-%d %H:%M:%S").to_string(),
+            ..Default::default()
+        };
+        Ok(RunDir { path, manifest })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Lists the files written into the run directory as artifacts and saves the manifest.
+    /// Returns the path of the manifest.
+    pub fn finish(mut self) -> Result<PathBuf, Box<dyn Error>> {
+        let mut artifacts = Vec::new();
+        for entry in fs::read_dir(&self.path)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().to_string();
+            if name != MANIFEST && entry.file_type()?.is_file() {
+                artifacts.push(Artifact {
+                    kind: artifact_kind(&name).to_string(),
+                    path: PathBuf::from(name),
+                });
+            }
+        }
+        artifacts.sort_by(|a, b| a.path.cmp(&b.path));
+        self.manifest.artifacts = artifacts;
+        self.manifest.finished_at = Some(Local::now().format("%Y-%m-%d %H:%M:%S").to_string());
+
+        let manifest_path = self.path.join(MANIFEST);
+        fs::write(&manifest_path, serde_json::to_string_pretty(&self.manifest)?)?;
+
+        println!("Run directory: {}", self.path.display());
+        for artifact in &self.manifest.artifacts {
+            println!("  {:<18} {}", artifact.kind, artifact.path.display());
+        }
+        Ok(manifest_path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Every invocation gets its own directory and the manifest lists what was written into it.
+    #[test]
+    fn test_run_dir_manifest() {
+        let base = std::env::temp_dir().join(format!("flame_test_run_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&base);
+
+        let mut run = RunDir::create(&base).expect("Failed to create run directory");
+        let second = RunDir::create(&base).expect("Failed to create second run directory");
+        assert_ne!(run.path(), second.path());
+
+        fs::write(run.path().join("stacktrace_1.txt"), "main 1\n").expect("Failed to write artifact");
+        fs::write(run.path().join("stacktrace_1.meta.json"), "{}").expect("Failed to write artifact");
+        run.manifest.ranks = vec![0, 1];
+        let manifest_path = run.finish().expect("Failed to finish run");
+
+        let manifest: Manifest =
+            serde_json::from_str(&fs::read_to_string(manifest_path).expect("Missing manifest")).expect("Invalid manifest");
+        assert_eq!(manifest.ranks, vec![0, 1]);
+        assert_eq!(
+            manifest.artifacts,
+            vec![
+                Artifact { kind: "frame_annotations".to_string(), path: PathBuf::from("stacktrace_1.meta.json") },
+                Artifact { kind: "merged_stacks".to_string(), path: PathBuf::from("stacktrace_1.txt") },
+            ]
+        );
+    }
+}
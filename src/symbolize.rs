@@ -0,0 +1,283 @@
+use addr2line::Loader;
+use object::{Object, ObjectSegment, ReadCache};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::process::Frame;
+
+/// Options of the offline native frame symbolization pass.
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct SymbolizeOptions {
+    /// Memory maps in `/proc/<pid>/maps` format; a `None` rank applies to every rank without its own map.
+    pub maps: Vec<(Option<u32>, PathBuf)>,
+    /// Directories holding local copies of the mapped shared libraries.
+    pub symbol_dirs: Vec<PathBuf>,
+}
+
+impl SymbolizeOptions {
+    pub fn is_enabled(&self) -> bool {
+        !self.maps.is_empty()
+    }
+}
+
+/// Parses a `--maps` argument of the form `[RANK:]PATH`.
+pub fn parse_maps_arg(arg: &str) -> Result<(Option<u32>, PathBuf), String> {
+    match arg.split_once(':') {
+        Some((rank, path)) if !rank.is_empty() && rank.chars().all(|c| c.is_ascii_digit()) => {
+            let rank = rank.parse::<u32>().map_err(|e| format!("Invalid rank '{}': {}", rank, e))?;
+            Ok((Some(rank), PathBuf::from(path)))
+        }
+        _ => Ok((None, PathBuf::from(arg))),
+    }
+}
+
+/// A file-backed region of a process address space.
+#[derive(Debug, Clone, PartialEq)]
+struct MemoryMapping {
+    start: u64,
+    end: u64,
+    offset: u64,
+    path: PathBuf,
+}
+
+/// Parses `/proc/<pid>/maps` content, keeping only file-backed mappings.
+fn parse_maps(content: &str) -> Vec<MemoryMapping> {
+    let mut mappings = Vec::new();
+    for line in content.lines() {
+        // start-end perms offset dev inode path
+        let mut fields = line.split_whitespace();
+        let (Some(range), Some(_perms), Some(offset), Some(_dev), Some(_inode)) =
+            (fields.next(), fields.next(), fields.next(), fields.next(), fields.next())
+        else {
+            continue;
+        };
+        let path: Vec<&str> = fields.collect();
+        let path = path.join(" ");
+        if !path.starts_with('/') {
+            continue;
+        }
+        let Some((start, end)) = range.split_once('-') else {
+            continue;
+        };
+        let (Ok(start), Ok(end), Ok(offset)) = (
+            u64::from_str_radix(start, 16),
+            u64::from_str_radix(end, 16),
+            u64::from_str_radix(offset, 16),
+        ) else {
+            continue;
+        };
+        mappings.push(MemoryMapping {
+            start,
+            end,
+            offset,
+            path: PathBuf::from(path),
+        });
+    }
+    mappings
+}
+
+/// DWARF data and load segments of one ELF file.
+struct ElfFile {
+    loader: Loader,
+    /// `(file offset, file size, virtual address)` of each segment.
+    segments: Vec<(u64, u64, u64)>,
+}
+
+impl ElfFile {
+    /// Reads the segment table through a cache holding only the parsed headers; the loader maps
+    /// the file itself for the DWARF data.
+    fn open(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let data = ReadCache::new(fs::File::open(path)?);
+        let object = object::File::parse(&data)?;
+        let segments = object
+            .segments()
+            .map(|segment| {
+                let (offset, size) = segment.file_range();
+                (offset, size, segment.address())
+            })
+            .collect();
+        let loader = Loader::new(path).map_err(|e| e.to_string())?;
+        Ok(ElfFile { loader, segments })
+    }
+
+    /// Converts a file offset into the virtual address used by the DWARF data.
+    fn offset_to_address(&self, file_offset: u64) -> Option<u64> {
+        self.segments
+            .iter()
+            .find(|(offset, size, _)| file_offset >= *offset && file_offset < offset + size)
+            .map(|(offset, _, address)| file_offset - offset + address)
+    }
+}
+
+/// Resolved source location of a native frame.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NativeLocation {
+    pub func: Option<String>,
+    pub file: String,
+    pub line: u32,
+}
+
+/// Resolves instruction pointers to source locations using local ELF files.
+pub struct Symbolizer {
+    symbol_dirs: Vec<PathBuf>,
+    /// Opened ELF files by mapped path; `None` when the file is missing or unreadable.
+    files: HashMap<PathBuf, Option<ElfFile>>,
+}
+
+impl Symbolizer {
+    pub fn new(symbol_dirs: Vec<PathBuf>) -> Self {
+        Symbolizer {
+            symbol_dirs,
+            files: HashMap::new(),
+        }
+    }
+
+    /// Finds the local copy of a mapped file: `<dir>/<full path>`, then `<dir>/<file name>`,
+    /// falling back to the mapped path itself.
+    fn locate(&self, mapped: &Path) -> Option<PathBuf> {
+        for dir in &self.symbol_dirs {
+            let relative = mapped.strip_prefix("/").unwrap_or(mapped);
+            let candidate = dir.join(relative);
+            if candidate.is_file() {
+                return Some(candidate);
+            }
+            if let Some(name) = mapped.file_name() {
+                let candidate = dir.join(name);
+                if candidate.is_file() {
+                    return Some(candidate);
+                }
+            }
+        }
+        mapped.is_file().then(|| mapped.to_path_buf())
+    }
+
+    fn elf_file(&mut self, mapped: &Path) -> Option<&ElfFile> {
+        if !self.files.contains_key(mapped) {
+            let file = self.locate(mapped).and_then(|path| match ElfFile::open(&path) {
+                Ok(file) => Some(file),
+                Err(e) => {
+                    eprintln!("Warning: Failed to load symbols from {}: {}", path.display(), e);
+                    None
+                }
+            });
+            self.files.insert(mapped.to_path_buf(), file);
+        }
+        self.files.get(mapped).and_then(Option::as_ref)
+    }
+
+    /// Resolves `ip` within the given memory map.
+    fn resolve(&mut self, mappings: &[MemoryMapping], ip: u64) -> Option<NativeLocation> {
+        let mapping = mappings.iter().find(|m| ip >= m.start && ip < m.end)?;
+        let file_offset = ip - mapping.start + mapping.offset;
+        let elf = self.elf_file(&mapping.path)?;
+        let probe = elf.offset_to_address(file_offset)?;
+
+        let location = elf.loader.find_location(probe).ok().flatten()?;
+        let file = location.file?.to_string();
+        let line = location.line.unwrap_or(0);
+
+        // The outermost entry of the inlined-frame chain is the function the address belongs to
+        let mut func = None;
+        if let Ok(mut frames) = elf.loader.find_frames(probe) {
+            while let Ok(Some(frame)) = frames.next() {
+                if let Some(name) = frame.function.as_ref().and_then(|f| f.demangle().ok()) {
+                    func = Some(name.into_owned());
+                }
+            }
+        }
+
+        Some(NativeLocation { func, file, line })
+    }
+}
+
+/// Fills in `file`/`lineno` of native frames that arrived without source information.
+/// `frames[i]` is the stack of `rank_list[i]`. Returns the number of resolved frames.
+pub fn symbolize_frames(
+    frames: &mut [Vec<Frame>],
+    rank_list: &[u32],
+    options: &SymbolizeOptions,
+) -> Result<usize, Box<dyn Error>> {
+    let mut default_maps = Vec::new();
+    let mut rank_maps = HashMap::new();
+    for (rank, path) in &options.maps {
+        let content = fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read memory map {}: {}", path.display(), e))?;
+        match rank {
+            Some(rank) => {
+                rank_maps.insert(*rank, parse_maps(&content));
+            }
+            None => default_maps = parse_maps(&content),
+        }
+    }
+
+    let mut symbolizer = Symbolizer::new(options.symbol_dirs.clone());
+    let mut resolved = 0;
+    for (trace, rank) in frames.iter_mut().zip(rank_list) {
+        let mappings = rank_maps.get(rank).unwrap_or(&default_maps);
+        for (depth, frame) in trace.iter_mut().enumerate() {
+            let Frame::CFrame(frame) = frame else {
+                continue;
+            };
+            if !frame.file.is_empty() && frame.lineno != 0 {
+                continue;
+            }
+            let Ok(mut ip) = u64::from_str_radix(frame.ip.trim_start_matches("0x"), 16) else {
+                continue;
+            };
+            // Outer frames hold return addresses; step back into the call instruction
+            if depth > 0 {
+                ip = ip.saturating_sub(1);
+            }
+            if let Some(location) = symbolizer.resolve(mappings, ip) {
+                frame.file = location.file;
+                frame.lineno = location.line;
+                if frame.func.is_empty() {
+                    frame.func = location.func.unwrap_or_default();
+                }
+                resolved += 1;
+            }
+        }
+    }
+    Ok(resolved)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[inline(never)]
+    fn symbolize_me() -> u32 {
+        42
+    }
+
+    /// Parses maps lines and resolves a function of the running test binary to this file.
+    #[test]
+    fn test_resolve_own_function() {
+        let maps = parse_maps(
+            "7f7b4a800000-7f7b4a900000 r-xp 00010000 08:01 1234 /usr/lib/libfoo.so\n\
+             7ffd3c1e0000-7ffd3c201000 rw-p 00000000 00:00 0 [stack]\n",
+        );
+        assert_eq!(
+            maps,
+            vec![MemoryMapping {
+                start: 0x7f7b4a800000,
+                end: 0x7f7b4a900000,
+                offset: 0x10000,
+                path: PathBuf::from("/usr/lib/libfoo.so"),
+            }]
+        );
+
+        let content = fs::read_to_string("/proc/self/maps").expect("Failed to read own memory map");
+        let mappings = parse_maps(&content);
+        let ip = symbolize_me as *const () as u64;
+        assert_eq!(symbolize_me(), 42);
+
+        let mut symbolizer = Symbolizer::new(Vec::new());
+        let location = symbolizer.resolve(&mappings, ip).expect("Failed to resolve own function");
+        assert!(location.file.ends_with("symbolize.rs"), "unexpected file {}", location.file);
+        assert!(location.line > 0);
+    }
+}
@@ -0,0 +1,313 @@
+use std::collections::BTreeSet;
+use std::error::Error;
+use std::path::Path;
+
+/// Parses a rank list such as `3`, `0-7` or `0-3,8-11`, keeping the given order.
+pub fn parse_rank_range(ranks: &str) -> Result<Vec<u32>, String> {
+    let mut list = Vec::new();
+    for part in ranks.split(',') {
+        let parse = |rank: &str| {
+            rank.trim()
+                .parse::<u32>()
+                .map_err(|_| format!("Invalid rank '{}' in '{}'", rank, ranks))
+        };
+        match part.split_once('-') {
+            Some((first, last)) => {
+                let (first, last) = (parse(first)?, parse(last)?);
+                if first > last {
+                    return Err(format!("Invalid rank range '{}', the first rank is larger than the last", part));
+                }
+                list.extend(first..=last);
+            }
+            None => list.push(parse(part)?),
+        }
+    }
+    Ok(list)
+}
+
+/// Splits a comma-separated host list. IPv6 addresses must be written in brackets,
+/// e.g. `[fe80::1]`, so that their colons are not taken for the port separator.
+fn parse_hosts(hosts: &str) -> Result<Vec<&str>, String> {
+    hosts
+        .split(',')
+        .map(|host| {
+            let host = host.trim();
+            if host.is_empty() {
+                Err(format!("Empty host in '{}'", hosts))
+            } else if host.contains(':') && !(host.starts_with('[') && host.ends_with(']')) {
+                Err(format!("IPv6 address '{}' must be written in brackets, e.g. [{}]", host, host))
+            } else {
+                Ok(host)
+            }
+        })
+        .collect()
+}
+
+/// Expands a `-r` rank spec `RANKS:HOSTS:PORT[+]` into `(rank, address)` pairs.
+///
+/// `RANKS` is a rank list as accepted by [`parse_rank_range`] and `HOSTS` a comma-separated
+/// list of IPv4 addresses, hostnames or bracketed IPv6 addresses. The ranks are split evenly
+/// over the hosts in order. With a trailing `+`, the ranks of a host get consecutive ports
+/// starting at `PORT`, the way `config_probing.sh` assigns them; without it every host
+/// serves a single rank on `PORT`.
+pub fn expand_rank_spec(spec: &str) -> Result<Vec<(u32, String)>, String> {
+    // 兼容旧格式 <RANK>:<IP:PORT>
+    let spec: String = spec.trim().chars().filter(|c| *c != '<' && *c != '>').collect();
+    let invalid = || format!("Invalid rank spec '{}', expected RANKS:HOSTS:PORT[+], e.g. 0-7:10.0.0.5:11490+", spec);
+
+    let (ranks, address) = spec.split_once(':').ok_or_else(invalid)?;
+    let (hosts, port) = address.rsplit_once(':').ok_or_else(invalid)?;
+    let (port, increment) = match port.strip_suffix('+') {
+        Some(port) => (port, true),
+        None => (port, false),
+    };
+    let port = port.parse::<u16>().map_err(|_| format!("Invalid port '{}' in '{}'", port, spec))?;
+    let ranks = parse_rank_range(ranks)?;
+    let hosts = parse_hosts(hosts)?;
+
+    if ranks.len() % hosts.len() != 0 {
+        return Err(format!(
+            "Cannot split {} ranks evenly over {} hosts in '{}'",
+            ranks.len(),
+            hosts.len(),
+            spec
+        ));
+    }
+    let per_host = ranks.len() / hosts.len();
+    if per_host > 1 && !increment {
+        return Err(format!(
+            "{} ranks per host need distinct ports in '{}', add '+' after the port to increment it",
+            per_host, spec
+        ));
+    }
+
+    let mut targets = Vec::with_capacity(ranks.len());
+    for (host, ranks) in hosts.iter().zip(ranks.chunks(per_host)) {
+        for (local_rank, rank) in ranks.iter().enumerate() {
+            let port = u16::try_from(port as usize + local_rank)
+                .map_err(|_| format!("Port out of range for rank {} in '{}'", rank, spec))?;
+            targets.push((*rank, format!("{}:{}", host, port)));
+        }
+    }
+    Ok(targets)
+}
+
+/// Expands the `-r` arguments into `(rank, address)` pairs. An argument `@FILE` reads the
+/// specs from FILE, one per line; blank lines and lines starting with `#` are skipped.
+pub fn expand_rank_args(args: &[String]) -> Result<Vec<(u32, String)>, Box<dyn Error>> {
+    let mut targets = Vec::new();
+    for arg in args {
+        match arg.strip_prefix('@') {
+            Some(file) => {
+                let content = std::fs::read_to_string(Path::new(file))
+                    .map_err(|e| format!("Failed to read rank file {}: {}", file, e))?;
+                for line in content.lines().map(str::trim) {
+                    if !line.is_empty() && !line.starts_with('#') {
+                        targets.extend(expand_rank_spec(line)?);
+                    }
+                }
+            }
+            None => targets.extend(expand_rank_spec(arg)?),
+        }
+    }
+
+    let mut seen = BTreeSet::new();
+    for (rank, _) in &targets {
+        if !seen.insert(*rank) {
+            return Err(format!("Rank {} is given more than once in the -r arguments", rank).into());
+        }
+    }
+    Ok(targets)
+}
+
+/// Expands the comma-separated items of a bracket list such as `001-016,020`, keeping the
+/// zero padding of the range bounds.
+fn expand_bracket(list: &str) -> Result<Vec<String>, String> {
+    let mut items = Vec::new();
+    for part in list.split(',') {
+        match part.split_once('-') {
+            Some((first, last)) => {
+                let parse = |bound: &str| {
+                    bound.parse::<u64>().map_err(|_| format!("Invalid range '{}' in nodelist", part))
+                };
+                let width = first.len();
+                let (start, end) = (parse(first)?, parse(last)?);
+                if start > end {
+                    return Err(format!("Invalid range '{}' in nodelist, the first index is larger than the last", part));
+                }
+                for index in start..=end {
+                    items.push(format!("{:0width$}", index, width = width));
+                }
+            }
+            None if !part.is_empty() => items.push(part.to_string()),
+            None => return Err(format!("Empty item in nodelist range '[{}]'", list)),
+        }
+    }
+    Ok(items)
+}
+
+/// Expands one host pattern, which may hold several bracket lists, e.g. `rack[1-2]-gpu[01-04]`.
+fn expand_host_pattern(pattern: &str) -> Result<Vec<String>, String> {
+    let unmatched = || format!("Unmatched ']' in nodelist '{}'", pattern);
+    let Some(open) = pattern.find('[') else {
+        if pattern.contains(']') {
+            return Err(unmatched());
+        }
+        return Ok(vec![pattern.to_string()]);
+    };
+    if pattern[..open].contains(']') {
+        return Err(unmatched());
+    }
+    let close = pattern[open..]
+        .find(']')
+        .map(|offset| open + offset)
+        .ok_or_else(|| format!("Unclosed '[' in nodelist '{}'", pattern))?;
+    let prefix = &pattern[..open];
+    let rest = expand_host_pattern(&pattern[close + 1..])?;
+    let mut hosts = Vec::new();
+    for item in expand_bracket(&pattern[open + 1..close])? {
+        for suffix in &rest {
+            hosts.push(format!("{}{}{}", prefix, item, suffix));
+        }
+    }
+    Ok(hosts)
+}
+
+/// Expands a Slurm nodelist such as `gpu[001-016,020],cpu01` into host names, in order,
+/// the way `scontrol show hostnames` does.
+pub fn expand_nodelist(nodelist: &str) -> Result<Vec<String>, String> {
+    let mut hosts = Vec::new();
+    let mut depth = 0;
+    let mut start = 0;
+    for (index, c) in nodelist.char_indices() {
+        match c {
+            '[' => depth += 1,
+            ']' => depth -= 1,
+            ',' if depth == 0 => {
+                hosts.extend(expand_host_pattern(nodelist[start..index].trim())?);
+                start = index + 1;
+            }
+            _ => {}
+        }
+    }
+    hosts.extend(expand_host_pattern(nodelist[start..].trim())?);
+    if hosts.iter().any(String::is_empty) {
+        return Err(format!("Empty host in nodelist '{}'", nodelist));
+    }
+    Ok(hosts)
+}
+
+/// A node listed in a hostfile.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HostfileEntry {
+    pub host: String,
+    /// Number of ranks on the node, if the hostfile gives it.
+    pub slots: Option<u32>,
+}
+
+/// Reads an MPI-style hostfile: one node per line, optionally followed by its slot count as
+/// `gpu001 slots=8` (Open MPI) or `gpu001:8` (MPICH). `#` starts a comment.
+pub fn read_hostfile(path: &Path) -> Result<Vec<HostfileEntry>, Box<dyn Error>> {
+    let content = std::fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read hostfile {}: {}", path.display(), e))?;
+    let mut nodes = Vec::new();
+    for (line_number, line) in content.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or_default();
+        let mut fields = line.split_whitespace();
+        let Some(host) = fields.next() else {
+            continue;
+        };
+        let invalid = || format!("Invalid slot count on line {} of {}: {}", line_number + 1, path.display(), line.trim());
+
+        let (host, mut slots) = match host.rsplit_once(':') {
+            // 未加括号的IPv6地址中的冒号不是分隔符
+            Some((name, slots)) if !name.contains(':') || name.ends_with(']') => {
+                (name, Some(slots.parse::<u32>().map_err(|_| invalid())?))
+            }
+            _ => (host, None),
+        };
+        for field in fields {
+            if let Some(count) = field.strip_prefix("slots=") {
+                slots = Some(count.parse::<u32>().map_err(|_| invalid())?);
+            }
+        }
+        nodes.push(HostfileEntry { host: host.to_string(), slots });
+    }
+    Ok(nodes)
+}
+
+/// Numbers the ranks of `nodes` globally in node order, each node running `slots` ranks that
+/// listen on consecutive ports from `base_port`. Returns `(rank, address)` pairs.
+pub fn node_rank_targets(nodes: &[(String, u32)], base_port: u16) -> Result<Vec<(u32, String)>, String> {
+    let mut targets = Vec::new();
+    for (host, slots) in nodes {
+        let host = if host.contains(':') && !host.starts_with('[') { format!("[{}]", host) } else { host.clone() };
+        for local_rank in 0..*slots {
+            let port = u16::try_from(base_port as u32 + local_rank)
+                .map_err(|_| format!("Port out of range for local rank {} on {}", local_rank, host))?;
+            targets.push((targets.len() as u32, format!("{}:{}", host, port)));
+        }
+    }
+    Ok(targets)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Ranges spread over host lists with auto-incremented ports, IPv6 and the legacy format.
+    #[test]
+    fn test_expand_rank_spec() {
+        assert_eq!(
+            expand_rank_spec("0-3:gpu001,gpu002:11490+").expect("Valid spec"),
+            vec![
+                (0, "gpu001:11490".to_string()),
+                (1, "gpu001:11491".to_string()),
+                (2, "gpu002:11490".to_string()),
+                (3, "gpu002:11491".to_string()),
+            ]
+        );
+        assert_eq!(
+            expand_rank_spec("<2>:<10.0.0.5:11492>").expect("Valid legacy spec"),
+            vec![(2, "10.0.0.5:11492".to_string())]
+        );
+        assert_eq!(
+            expand_rank_spec("4,6:[fe80::1]:12000+").expect("Valid IPv6 spec"),
+            vec![(4, "[fe80::1]:12000".to_string()), (6, "[fe80::1]:12001".to_string())]
+        );
+        assert!(expand_rank_spec("0:fe80::1:11490").is_err());
+        assert!(expand_rank_spec("0-7:10.0.0.5:11490").is_err());
+        assert!(expand_rank_spec("0-2:gpu001,gpu002:11490+").is_err());
+    }
+
+    /// Slurm nodelists and hostfile slots map to global ranks in node order.
+    #[test]
+    fn test_nodelist_and_hostfile() {
+        assert_eq!(
+            expand_nodelist("gpu[008-010,020],rack[1-2]-n[1,3],login").expect("Valid nodelist"),
+            vec!["gpu008", "gpu009", "gpu010", "gpu020", "rack1-n1", "rack1-n3", "rack2-n1", "rack2-n3", "login"]
+        );
+        assert!(expand_nodelist("gpu[001-004").is_err());
+        assert!(expand_nodelist("gpu[10-01]").is_err());
+        assert!(expand_nodelist("node1]").is_err());
+        assert!(expand_nodelist("gpu[1-2]],cpu01").is_err());
+
+        let path = std::env::temp_dir().join(format!("flame_test_hostfile_{}", std::process::id()));
+        std::fs::write(&path, "# training nodes\ngpu001 slots=2\ngpu002:1\n\ngpu003 # default slots\n")
+            .expect("Failed to write hostfile");
+        let nodes = read_hostfile(&path).expect("Valid hostfile");
+        let slots: Vec<(&str, Option<u32>)> = nodes.iter().map(|entry| (entry.host.as_str(), entry.slots)).collect();
+        assert_eq!(slots, vec![("gpu001", Some(2)), ("gpu002", Some(1)), ("gpu003", None)]);
+
+        let nodes: Vec<(String, u32)> = nodes.into_iter().map(|entry| (entry.host, entry.slots.unwrap_or(1))).collect();
+        assert_eq!(
+            node_rank_targets(&nodes, 11490).expect("Valid ports"),
+            vec![
+                (0, "gpu001:11490".to_string()),
+                (1, "gpu001:11491".to_string()),
+                (2, "gpu002:11490".to_string()),
+                (3, "gpu003:11490".to_string()),
+            ]
+        );
+    }
+}